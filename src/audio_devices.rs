@@ -0,0 +1,117 @@
+use crate::config::AudioConfig;
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::error::Error;
+
+/// Returns the cpal host named in the config, or the platform default.
+///
+/// Only hosts compiled into this build are available; JACK requires the
+/// `jack` cargo feature.
+pub fn select_host(name: Option<&str>) -> Result<cpal::Host, Box<dyn Error>> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let available: Vec<&str> = cpal::available_hosts().iter().map(|id| id.name()).collect();
+            format!(
+                "Audio host '{}' is not available. Available hosts: {}",
+                name,
+                available.join(", ")
+            )
+        })?;
+
+    Ok(cpal::host_from_id(host_id)?)
+}
+
+/// Finds the input device to record from.
+///
+/// An exact (case-insensitive) name match wins over a substring match, so
+/// `device = "USB"` picks the first device whose name contains "USB".
+pub fn select_input_device(
+    host: &cpal::Host,
+    name: Option<&str>,
+) -> Result<cpal::Device, Box<dyn Error>> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            return host
+                .default_input_device()
+                .ok_or_else(|| "No input device available".into())
+        }
+    };
+
+    let needle = name.to_lowercase();
+    let mut partial_match = None;
+
+    for device in host.input_devices()? {
+        let device_name = match device.name() {
+            Ok(device_name) => device_name.to_lowercase(),
+            Err(_) => continue,
+        };
+
+        if device_name == needle {
+            return Ok(device);
+        }
+        if partial_match.is_none() && device_name.contains(&needle) {
+            partial_match = Some(device);
+        }
+    }
+
+    partial_match.ok_or_else(|| {
+        format!(
+            "Input device '{}' not found. Run with --list-devices to see available inputs.",
+            name
+        )
+        .into()
+    })
+}
+
+/// Opens the host and input device configured in `[audio]`.
+pub fn open_input_device(config: &AudioConfig) -> Result<cpal::Device, Box<dyn Error>> {
+    let host = select_host(config.host.as_deref())?;
+    select_input_device(&host, config.device.as_deref())
+}
+
+/// Prints every input device of every available host with its default config.
+pub fn list_input_devices() -> Result<(), Box<dyn Error>> {
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("Host {}: unavailable ({})", host_id.name(), e);
+                continue;
+            }
+        };
+
+        println!("Host {}:", host_id.name());
+
+        let default_name = host.default_input_device().and_then(|d| d.name().ok());
+
+        for device in host.input_devices()? {
+            let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+            let marker = if Some(&name) == default_name.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+
+            match device.default_input_config() {
+                Ok(config) => println!(
+                    "  {}{} - {} channel(s), {} Hz, {}",
+                    name,
+                    marker,
+                    config.channels(),
+                    config.sample_rate().0,
+                    config.sample_format()
+                ),
+                Err(e) => println!("  {}{} - no default config ({})", name, marker, e),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use crate::config::AudioConfig;
+use crate::flac::encode_flac;
+use crate::input_levels::InputLevels;
+use hound::WavWriter;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::Cursor;
+use std::time::Duration;
+
+/// Container and codec used for the audio uploaded for transcription.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadFormat {
+    /// Uncompressed 16-bit PCM.
+    #[default]
+    Wav,
+    /// Lossless, usually about half the size of WAV for speech.
+    Flac,
+    /// Ogg/Opus, lossy and by far the smallest. Needs the `opus` feature.
+    Opus,
+}
+
+impl UploadFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            UploadFormat::Wav => "wav",
+            UploadFormat::Flac => "flac",
+            UploadFormat::Opus => "ogg",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            UploadFormat::Wav => "audio/wav",
+            UploadFormat::Flac => "audio/flac",
+            UploadFormat::Opus => "audio/ogg",
+        }
+    }
+}
+
+/// An utterance encoded for upload, along with the mono samples it was
+/// encoded from.
+#[derive(Clone)]
+pub struct EncodedAudio {
+    pub data: Vec<u8>,
+    pub format: UploadFormat,
+    pub duration: Duration,
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    /// Input levels measured by the recorder, if it measured them.
+    pub levels: Option<InputLevels>,
+}
+
+impl EncodedAudio {
+    /// File name for the multipart upload, so the API can tell the format apart.
+    pub fn file_name(&self) -> String {
+        format!("audio.{}", self.format.extension())
+    }
+}
+
+/// Encodes conditioned mono samples in the format selected by
+/// `[audio] upload_format`.
+#[derive(Clone)]
+pub struct AudioEncoder {
+    format: UploadFormat,
+    opus_bitrate: i32,
+}
+
+impl AudioEncoder {
+    pub fn new(config: &AudioConfig) -> Self {
+        AudioEncoder {
+            format: config.upload_format,
+            opus_bitrate: config.opus_bitrate,
+        }
+    }
+
+    pub fn encode(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+    ) -> Result<EncodedAudio, Box<dyn Error>> {
+        let data = match self.format {
+            UploadFormat::Wav => encode_wav_pcm16(samples, sample_rate)?,
+            UploadFormat::Flac => encode_flac(samples, sample_rate),
+            UploadFormat::Opus => encode_opus(samples, sample_rate, self.opus_bitrate)?,
+        };
+
+        Ok(EncodedAudio {
+            data,
+            format: self.format,
+            duration: Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64),
+            samples: samples.to_vec(),
+            sample_rate,
+            levels: None,
+        })
+    }
+}
+
+/// Encodes mono float samples as a 16-bit PCM WAV file.
+pub fn encode_wav_pcm16(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut wav_buffer = Vec::new();
+    {
+        let mut writer = WavWriter::new(
+            Cursor::new(&mut wav_buffer),
+            hound::WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+        )?;
+
+        for &sample in samples {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()?;
+    }
+    Ok(wav_buffer)
+}
+
+#[cfg(feature = "opus")]
+fn encode_opus(samples: &[f32], sample_rate: u32, bitrate: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+    use crate::audio_conditioning::resample;
+    use crate::ogg_opus::{encode_ogg_opus, OPUS_SAMPLE_RATES};
+
+    if OPUS_SAMPLE_RATES.contains(&sample_rate) {
+        encode_ogg_opus(samples, sample_rate, bitrate)
+    } else {
+        // Opus only takes a few fixed rates; 16 kHz keeps the full speech band
+        let resampled = resample(samples, sample_rate, 16000)?;
+        encode_ogg_opus(&resampled, 16000, bitrate)
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+fn encode_opus(_: &[f32], _: u32, _: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("Opus uploads need the `opus` feature, rebuild with `cargo build --features opus`".into())
+}
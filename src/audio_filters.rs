@@ -0,0 +1,420 @@
+use crate::audio_conditioning::{downmix_to_mono, mix_channels};
+use crate::config::AudioConfig;
+use crate::voice_activity::db_to_ratio;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::error::Error;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Cleans up captured audio before it reaches the voice activity detector.
+///
+/// Interleaved input is mixed down to mono from the channels selected in
+/// `[audio] channels`, or from all of them, then run through the high-pass
+/// filter and the noise reducer when they are enabled in `[audio]`.
+pub struct Preprocessor {
+    channels: u16,
+    selected_channels: Option<Vec<usize>>,
+    high_pass: Option<HighPassFilter>,
+    noise_reducer: Option<NoiseReducer>,
+    /// Frames where a selected input was at full scale, before any filtering.
+    clipped_frames: usize,
+}
+
+impl Preprocessor {
+    pub fn new(
+        config: &AudioConfig,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<Self, Box<dyn Error>> {
+        let selected_channels = match &config.channels {
+            Some(selected) => Some(select_channels(selected, channels)?),
+            None => None,
+        };
+
+        Ok(Preprocessor {
+            channels,
+            selected_channels,
+            high_pass: config
+                .high_pass
+                .then(|| HighPassFilter::new(config.high_pass_hz, sample_rate)),
+            noise_reducer: config
+                .noise_reduction
+                .then(|| NoiseReducer::new(config.noise_reduction_db, sample_rate)),
+            clipped_frames: 0,
+        })
+    }
+
+    /// Turns a block of interleaved samples into filtered mono samples.
+    ///
+    /// `learn_noise` should be set while nobody is speaking, so the noise
+    /// reducer can update its profile of the background. The noise reducer
+    /// works on whole frames, so the result may be shorter or longer than the
+    /// block that went in.
+    pub fn process(&mut self, samples: &[f32], learn_noise: bool) -> Vec<f32> {
+        self.clipped_frames += self.count_clipped_frames(samples);
+
+        let mut mono = match &self.selected_channels {
+            Some(selected) => mix_channels(samples, self.channels, selected),
+            None => downmix_to_mono(samples, self.channels),
+        };
+
+        if let Some(filter) = &mut self.high_pass {
+            filter.process(&mut mono);
+        }
+
+        if let Some(reducer) = &mut self.noise_reducer {
+            reducer.process(&mut mono, learn_noise);
+        }
+
+        mono
+    }
+
+    /// Returns how many clipped frames have been seen since the last call.
+    pub fn take_clipped_frames(&mut self) -> usize {
+        std::mem::take(&mut self.clipped_frames)
+    }
+
+    /// Filters and mixing both hide clipping, so it is counted on the raw
+    /// input of the channels that are actually used.
+    fn count_clipped_frames(&self, samples: &[f32]) -> usize {
+        let channels = self.channels.max(1) as usize;
+        samples
+            .chunks_exact(channels)
+            .filter(|frame| match &self.selected_channels {
+                Some(selected) => selected.iter().any(|&i| frame[i].abs() >= CLIP_LEVEL),
+                None => frame.iter().any(|s| s.abs() >= CLIP_LEVEL),
+            })
+            .count()
+    }
+}
+
+/// Samples this close to full scale are counted as clipped.
+const CLIP_LEVEL: f32 = 0.999;
+
+/// Checks `[audio] channels` against a device with `channels` inputs.
+pub fn check_channels(config: &AudioConfig, channels: u16) -> Result<(), Box<dyn Error>> {
+    match &config.channels {
+        Some(selected) => select_channels(selected, channels).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Converts the 1-based channel numbers from the config into indices,
+/// checking them against what the device provides.
+fn select_channels(selected: &[u16], channels: u16) -> Result<Vec<usize>, Box<dyn Error>> {
+    if selected.is_empty() {
+        return Err("`channels` must list at least one input channel".into());
+    }
+
+    selected
+        .iter()
+        .map(|&channel| {
+            if channel == 0 || channel > channels {
+                Err(format!(
+                    "`channels` selects input {}, but the device has {} channel(s) numbered from 1",
+                    channel, channels
+                )
+                .into())
+            } else {
+                Ok(channel as usize - 1)
+            }
+        })
+        .collect()
+}
+
+/// Second-order Butterworth high-pass filter for rumble, hum and desk thumps.
+pub struct HighPassFilter {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl HighPassFilter {
+    pub fn new(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let omega = 2.0 * PI * cutoff_hz / sample_rate as f32;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+
+        HighPassFilter {
+            b0: (1.0 + cos) / 2.0 / a0,
+            b1: -(1.0 + cos) / a0,
+            b2: (1.0 + cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let input = *sample;
+            let output = self.b0 * input + self.z1;
+            self.z1 = self.b1 * input - self.a1 * output + self.z2;
+            self.z2 = self.b2 * input - self.a2 * output;
+            *sample = output;
+        }
+    }
+}
+
+/// How much of each new quiet frame goes into the noise profile.
+const NOISE_PROFILE_RATE: f32 = 0.05;
+/// Subtracts a bit more than the measured noise to keep residual hiss down.
+const OVER_SUBTRACTION: f32 = 2.0;
+/// Averages each bin's gain with the previous frame's to avoid "musical noise".
+const GAIN_SMOOTHING: f32 = 0.5;
+
+/// Spectral subtraction against a noise profile learned from quiet frames.
+///
+/// Audio is processed in half-overlapping frames of about 32 ms, which adds
+/// one frame of latency. Until the first quiet frame has been seen the
+/// reducer passes audio through unchanged.
+pub struct NoiseReducer {
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    hop: usize,
+    min_gain: f32,
+    noise_profile: Option<Vec<f32>>,
+    gains: Vec<f32>,
+    input: Vec<f32>,
+    overlap: Vec<f32>,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+}
+
+impl NoiseReducer {
+    /// `max_reduction_db` limits how far any frequency is turned down.
+    pub fn new(max_reduction_db: f32, sample_rate: u32) -> Self {
+        let fft_len = (sample_rate as usize * 32 / 1000)
+            .next_power_of_two()
+            .max(64);
+        let hop = fft_len / 2;
+
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(fft_len);
+        let inverse = planner.plan_fft_inverse(fft_len);
+        let spectrum = forward.make_output_vec();
+
+        // Square-root Hann on both analysis and synthesis sums to one at 50% overlap
+        let window = (0..fft_len)
+            .map(|i| (PI * i as f32 / fft_len as f32).sin())
+            .collect();
+
+        NoiseReducer {
+            window,
+            hop,
+            min_gain: db_to_ratio(-max_reduction_db.abs()),
+            noise_profile: None,
+            gains: vec![1.0; spectrum.len()],
+            // Primed so every hop of input yields a hop of output
+            input: vec![0.0; fft_len - hop],
+            overlap: vec![0.0; fft_len],
+            frame: forward.make_input_vec(),
+            spectrum,
+            forward,
+            inverse,
+        }
+    }
+
+    /// Replaces `samples` with the denoised audio that is ready so far.
+    pub fn process(&mut self, samples: &mut Vec<f32>, learn_noise: bool) {
+        self.input.extend_from_slice(samples);
+        samples.clear();
+
+        let fft_len = self.window.len();
+        while self.input.len() >= fft_len {
+            self.process_frame(learn_noise);
+            samples.extend_from_slice(&self.overlap[..self.hop]);
+            self.overlap.copy_within(self.hop.., 0);
+            self.overlap[fft_len - self.hop..].fill(0.0);
+            self.input.drain(..self.hop);
+        }
+    }
+
+    fn process_frame(&mut self, learn_noise: bool) {
+        let fft_len = self.window.len();
+        for ((frame, &input), &window) in self.frame.iter_mut().zip(&self.input).zip(&self.window) {
+            *frame = input * window;
+        }
+        self.forward
+            .process(&mut self.frame, &mut self.spectrum)
+            .expect("buffers are sized for the FFT");
+
+        if learn_noise {
+            let profile = self
+                .noise_profile
+                .get_or_insert_with(|| self.spectrum.iter().map(|bin| bin.norm_sqr()).collect());
+            for (noise, bin) in profile.iter_mut().zip(&self.spectrum) {
+                *noise += (bin.norm_sqr() - *noise) * NOISE_PROFILE_RATE;
+            }
+        }
+
+        if let Some(profile) = &self.noise_profile {
+            let min_power_gain = self.min_gain * self.min_gain;
+            for ((bin, gain), &noise) in self.spectrum.iter_mut().zip(&mut self.gains).zip(profile)
+            {
+                let power = bin.norm_sqr().max(f32::MIN_POSITIVE);
+                let target = (1.0 - OVER_SUBTRACTION * noise / power)
+                    .max(min_power_gain)
+                    .sqrt();
+                *gain += (target - *gain) * GAIN_SMOOTHING;
+                *bin *= *gain;
+            }
+        }
+
+        self.inverse
+            .process(&mut self.spectrum, &mut self.frame)
+            .expect("buffers are sized for the FFT");
+
+        let scale = 1.0 / fft_len as f32;
+        for ((overlap, &sample), &window) in
+            self.overlap.iter_mut().zip(&self.frame).zip(&self.window)
+        {
+            *overlap += sample * window * scale;
+        }
+    }
+}
+
+/// Frames quieter than this are left out of the loudness measurement.
+const LOUDNESS_GATE_DB: f32 = -50.0;
+/// Keeps normalization from turning a quiet room into a loud hiss.
+const MAX_NORMALIZATION_GAIN_DB: f32 = 30.0;
+
+/// Scales an utterance so the speech in it averages `target_db` RMS, without
+/// letting any peak exceed `peak_db`. Both levels are in dBFS.
+///
+/// Loudness is measured over 10 ms frames, ignoring pauses, so a short
+/// sentence with long gaps is not boosted more than a continuous one.
+pub fn normalize(samples: &mut [f32], sample_rate: u32, target_db: f32, peak_db: f32) {
+    let rms = match speech_level(samples, sample_rate) {
+        Some(rms) => rms,
+        None => return,
+    };
+    let peak = samples.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+
+    let gain = (db_to_ratio(target_db) / rms)
+        .min(db_to_ratio(peak_db) / peak)
+        .min(db_to_ratio(MAX_NORMALIZATION_GAIN_DB));
+    for sample in samples {
+        *sample *= gain;
+    }
+}
+
+/// RMS level of the speech in `samples`, measured over 10 ms frames and
+/// ignoring pauses. Returns `None` when every frame is below the gate.
+pub fn speech_level(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let frame_len = (sample_rate as usize / 100).max(1);
+    let gate = db_to_ratio(LOUDNESS_GATE_DB);
+
+    let (energy, count) = samples
+        .chunks(frame_len)
+        .map(|frame| frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32)
+        .filter(|&mean_square| mean_square.sqrt() > gate)
+        .fold((0.0, 0usize), |(energy, count), mean_square| {
+            (energy + mean_square, count + 1)
+        });
+
+    (count > 0).then(|| (energy / count as f32).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    fn sine(frequency: f32, amplitude: f32, seconds: f32) -> Vec<f32> {
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    fn noise(amplitude: f32, seconds: f32) -> Vec<f32> {
+        let mut seed = 1u32;
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Gain in dB of the high-pass filter for a sine, once it has settled.
+    fn high_pass_gain_db(frequency: f32) -> f32 {
+        let mut samples = sine(frequency, 0.5, 1.0);
+        HighPassFilter::new(80.0, SAMPLE_RATE).process(&mut samples);
+        let settled = &samples[samples.len() / 2..];
+        20.0 * (rms(settled) / rms(&sine(frequency, 0.5, 1.0))).log10()
+    }
+
+    #[test]
+    fn high_pass_removes_rumble_and_keeps_speech() {
+        assert!((high_pass_gain_db(80.0) + 3.0).abs() < 0.5);
+        assert!(high_pass_gain_db(20.0) < -20.0);
+        assert!(high_pass_gain_db(1000.0).abs() < 0.1);
+
+        let mut dc = vec![0.3; SAMPLE_RATE as usize];
+        HighPassFilter::new(80.0, SAMPLE_RATE).process(&mut dc);
+        assert!(dc[dc.len() - 1].abs() < 1e-4);
+    }
+
+    #[test]
+    fn noise_reducer_turns_down_learned_noise() {
+        let mut reducer = NoiseReducer::new(12.0, SAMPLE_RATE);
+        let mut background = noise(0.05, 1.0);
+        reducer.process(&mut background, true);
+
+        let mut noisy = noise(0.05, 1.0);
+        let before = rms(&noisy);
+        reducer.process(&mut noisy, false);
+        let reduction_db = 20.0 * (rms(&noisy) / before).log10();
+        assert!(reduction_db < -6.0, "only reduced by {} dB", reduction_db);
+
+        // A tone well above the noise comes through at about the same level
+        let mut tone = sine(440.0, 0.5, 1.0);
+        let before = rms(&tone);
+        reducer.process(&mut tone, false);
+        let settled = &tone[tone.len() / 2..];
+        assert!((20.0 * (rms(settled) / before).log10()).abs() < 1.0);
+    }
+
+    #[test]
+    fn normalize_reaches_target_level() {
+        let mut samples = sine(300.0, 0.01, 1.0);
+        normalize(&mut samples, SAMPLE_RATE, -20.0, -1.0);
+        let level_db = 20.0 * rms(&samples).log10();
+        assert!((level_db + 20.0).abs() < 0.1, "level is {} dBFS", level_db);
+    }
+
+    #[test]
+    fn normalize_never_pushes_peaks_above_limit() {
+        // Quiet speech with one loud click, so the target level would clip
+        let mut samples = sine(300.0, 0.01, 1.0);
+        samples[8000] = 0.5;
+        normalize(&mut samples, SAMPLE_RATE, -20.0, -1.0);
+        let peak = samples.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+        assert!(peak <= db_to_ratio(-1.0) + 1e-6, "peak is {}", peak);
+        assert!(peak > db_to_ratio(-1.0) - 1e-3);
+    }
+
+    #[test]
+    fn normalize_leaves_silence_alone() {
+        let mut samples = noise(0.0001, 1.0);
+        let before = samples.clone();
+        normalize(&mut samples, SAMPLE_RATE, -20.0, -1.0);
+        assert_eq!(samples, before);
+    }
+}
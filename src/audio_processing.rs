@@ -1,26 +1,164 @@
-use crate::chatbox::send_to_chatbox;
+use crate::audio_encoding::EncodedAudio;
+use crate::chatbox::{send_partial_to_chatbox, send_to_chatbox};
 use crate::config::Config;
 use crate::price_estimator::PriceEstimator;
 use crate::rate_limiter::RateLimiter;
 use crate::recording_manager::RecordingManager;
-use crate::transcription::transcribe_audio;
+use crate::transcription::Transcriber;
 use crate::translation::ask_chatgpt;
 use crate::typing_indicator::TypingIndicator;
 
 use std::error::Error;
-use std::time::Duration;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
 use tokio::net::UdpSocket;
 
+/// The transcription engine and request budget of one pipeline. The
+/// microphone and the listening pipeline each have their own, so neither
+/// waits on the other.
+pub struct Translator {
+    pub transcriber: Box<dyn Transcriber>,
+    pub rate_limiter: RateLimiter,
+}
+
+impl Translator {
+    pub fn new(transcriber: Box<dyn Transcriber>, config: &Config) -> Self {
+        Translator {
+            transcriber,
+            rate_limiter: RateLimiter::new(config.rate_limit.requests_per_minute),
+        }
+    }
+}
+
 pub async fn process_audio(
-    audio_data: Vec<u8>,
+    audio: EncodedAudio,
     config: &Config,
+    translator: &mut Translator,
     socket: &UdpSocket,
-    rate_limiter: &mut RateLimiter,
     typing_indicator: &TypingIndicator,
-    price_estimator: &mut PriceEstimator,
+    price_estimator: &PriceEstimator,
     recording_manager: Option<&RecordingManager>,
 ) -> Result<(), Box<dyn Error>> {
-    let audio_duration = calculate_audio_duration(&audio_data)?;
+    let shown = match translate_audio(
+        audio,
+        config,
+        translator,
+        price_estimator,
+        recording_manager,
+    )
+    .await
+    {
+        Ok(Some((transcription, mut response))) => {
+            if config.translation.include_original_message {
+                response = response + "\n" + &transcription;
+            }
+            send_to_chatbox(&response, config, socket)
+                .await
+                .map(|()| true)
+        }
+        Ok(None) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    // Partial text stays in the chatbox input until something replaces it,
+    // so clear it whenever no translation was shown, errors included
+    if config.transcription.partial_interval_ms.is_some() && !matches!(shown, Ok(true)) {
+        if let Err(e) = send_partial_to_chatbox("", config, socket).await {
+            eprintln!("Error clearing partial transcription: {}", e);
+        }
+    }
+
+    typing_indicator.stop_typing().await;
+
+    shown.map(|_| ())
+}
+
+/// Transcribes a snapshot of what is still being said and shows it in the
+/// chatbox until the translation of the whole utterance replaces it.
+///
+/// Snapshots are skipped rather than delayed when the rate limit is close,
+/// and never take the last request of the minute, which the whole utterance
+/// needs.
+pub async fn process_partial_audio(
+    audio: EncodedAudio,
+    config: &Config,
+    translator: &mut Translator,
+    socket: &UdpSocket,
+    price_estimator: &PriceEstimator,
+) -> Result<(), Box<dyn Error>> {
+    let min_duration = Duration::from_secs_f32(config.audio.min_transcription_duration);
+    if audio.duration < min_duration {
+        return Ok(());
+    }
+    if !translator.transcriber.is_free() && !translator.rate_limiter.try_acquire(1) {
+        if config.debug {
+            println!("Rate limit reached, skipping partial transcription");
+        }
+        return Ok(());
+    }
+
+    let audio_duration = audio.duration;
+    let transcription = translator.transcriber.transcribe_partial(audio).await?;
+    if !translator.transcriber.is_free() {
+        price_estimator.add_cost(price_estimator.estimate_transcription_cost(audio_duration));
+    }
+
+    if !transcription.is_empty() {
+        println!("Partial transcription: {}", transcription);
+        send_partial_to_chatbox(&transcription, config, socket).await?;
+    }
+
+    Ok(())
+}
+
+/// Translates what another player said, picked up by the listening pipeline,
+/// and shows it on the console and optionally appends it to `output_file`.
+pub async fn process_heard_audio(
+    audio: EncodedAudio,
+    config: &Config,
+    translator: &mut Translator,
+    output_file: Option<&Path>,
+    price_estimator: &PriceEstimator,
+) -> Result<(), Box<dyn Error>> {
+    println!("Translating what another player said...");
+    let result = translate_audio(audio, config, translator, price_estimator, None).await?;
+    let (transcription, translation) = match result {
+        Some(result) => result,
+        None => return Ok(()),
+    };
+
+    if let Some(path) = output_file {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(
+            format!("[{}] {}\n{}\n\n", timestamp, transcription, translation).as_bytes(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Transcribes and translates one utterance into `config.translation.target_language`,
+/// returning the transcription and the translation. Returns `None` for
+/// utterances too short to be worth transcribing.
+///
+/// Each utterance counts once against the rate limit, whatever the engine:
+/// even when transcribing locally, the translation is an API request.
+async fn translate_audio(
+    audio: EncodedAudio,
+    config: &Config,
+    translator: &mut Translator,
+    price_estimator: &PriceEstimator,
+    recording_manager: Option<&RecordingManager>,
+) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let audio_duration = audio.duration;
 
     let min_duration = Duration::from_secs_f32(config.audio.min_transcription_duration);
     if audio_duration < min_duration {
@@ -29,50 +167,256 @@ pub async fn process_audio(
             audio_duration.as_secs_f32(),
             min_duration.as_secs_f32()
         );
-        typing_indicator.stop_typing().await;
-        return Ok(());
+        return Ok(None);
     }
 
-    let transcription = transcribe_audio(audio_data.clone(), &config.openai, rate_limiter).await?;
-    println!("Transcription: {}", transcription);
+    translator.rate_limiter.wait().await;
+    let transcription = translator.transcriber.transcribe(audio.clone()).await?;
+    let transcription_cost = if translator.transcriber.is_free() {
+        0.0
+    } else {
+        price_estimator.estimate_transcription_cost(audio_duration)
+    };
 
     // Save the audio recording if debug mode is enabled
     if let Some(manager) = recording_manager {
-        manager.save_recording(audio_data, &transcription).await?;
+        manager.save_recording(audio, &transcription).await?;
     }
 
+    if transcription.is_empty() {
+        println!("Nothing left to translate after filtering.");
+        price_estimator.add_cost(transcription_cost);
+        println!("---");
+        return Ok(None);
+    }
+    println!("Transcription: {}", transcription);
+
     let translation_prompt = format!(
         "You are a language translation app for VRChat. Do not answer the user. Only translate the words the user said. Answer only in the target language. Do not quote the translation. target_language={} Text:\n\n{}",
         config.translation.target_language, transcription
     );
 
-    let mut response = ask_chatgpt(&translation_prompt, &config.openai).await?;
+    let translation_api = config.translation_api();
+    let response = ask_chatgpt(&translation_prompt, &translation_api).await?;
     println!("Translation: {}", response);
 
-    let transcription_cost = price_estimator.estimate_transcription_cost(audio_duration);
-    let input_tokens = translation_prompt.len() / 4;
-    let output_tokens = response.len() / 4;
-    let translation_cost = price_estimator.estimate_translation_cost(input_tokens, output_tokens);
+    // Other servers are not billed at OpenAI's prices, if at all
+    let translation_cost = if translation_api.is_openai() {
+        let input_tokens = translation_prompt.len() / 4;
+        let output_tokens = response.len() / 4;
+        price_estimator.estimate_translation_cost(input_tokens, output_tokens)
+    } else {
+        0.0
+    };
     let total_cost = transcription_cost + translation_cost;
 
     price_estimator.add_cost(total_cost);
     println!("Estimated cost for this operation: ${:.4}", total_cost);
-    println!("Total cost so far: ${:.4}", price_estimator.total_cost);
+    println!("Total cost so far: ${:.4}", price_estimator.total_cost());
     println!("---");
 
-    if config.translation.include_original_message {
-        response = response + "\n" + &transcription;
+    Ok(Some((transcription, response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_encoding::AudioEncoder;
+    use crate::test_support::{serve_chat_completions, temp_dir, test_config};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Engine that "hears" the same text every time and counts the calls.
+    struct StubTranscriber {
+        text: String,
+        free: bool,
+        calls: Arc<AtomicUsize>,
     }
-    send_to_chatbox(&response, &config, socket).await?;
 
-    typing_indicator.stop_typing().await;
+    #[async_trait]
+    impl Transcriber for StubTranscriber {
+        async fn transcribe(&mut self, _: EncodedAudio) -> Result<String, Box<dyn Error>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.text.clone())
+        }
 
-    Ok(())
-}
+        async fn transcribe_partial(
+            &mut self,
+            audio: EncodedAudio,
+        ) -> Result<String, Box<dyn Error>> {
+            self.transcribe(audio).await
+        }
+
+        fn is_free(&self) -> bool {
+            self.free
+        }
+
+        fn fork(&self, _: &Config) -> Box<dyn Transcriber> {
+            Box::new(StubTranscriber {
+                text: self.text.clone(),
+                free: self.free,
+                calls: Arc::clone(&self.calls),
+            })
+        }
+    }
+
+    fn translator(config: &Config, text: &str, free: bool) -> (Translator, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let transcriber = StubTranscriber {
+            text: text.to_string(),
+            free,
+            calls: Arc::clone(&calls),
+        };
+        (Translator::new(Box::new(transcriber), config), calls)
+    }
+
+    fn utterance(config: &Config, seconds: f32) -> EncodedAudio {
+        let samples = vec![0.1; (seconds * 16000.0) as usize];
+        AudioEncoder::new(&config.audio)
+            .encode(&samples, 16000)
+            .unwrap()
+    }
+
+    fn price_estimator(test: &str) -> PriceEstimator {
+        PriceEstimator::with_cost_file("gpt-4o-mini", temp_dir(test).join("total_cost.txt"))
+    }
+
+    /// Points translation at a port nothing listens on, so a request fails.
+    fn without_translation_server(config: &mut Config) {
+        config.translation.provider.base_url = Some("http://127.0.0.1:9".to_string());
+    }
+
+    #[tokio::test]
+    async fn skips_utterances_below_min_duration() {
+        let mut config = test_config("");
+        without_translation_server(&mut config);
+        let (mut translator, calls) = translator(&config, "hello", false);
+        let prices = price_estimator("skips_short");
 
-fn calculate_audio_duration(audio_data: &[u8]) -> Result<Duration, Box<dyn Error>> {
-    let reader = hound::WavReader::new(std::io::Cursor::new(audio_data))?;
-    let spec = reader.spec();
-    let duration = Duration::from_secs_f32(reader.duration() as f32 / spec.sample_rate as f32);
-    Ok(duration)
+        let audio = utterance(&config, 0.5);
+        let result = translate_audio(audio, &config, &mut translator, &prices, None).await;
+        assert!(result.unwrap().is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(prices.total_cost(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn translates_what_the_engine_heard() {
+        let mut config = test_config("");
+        config.translation.provider.base_url = Some(serve_chat_completions("こんにちは").await);
+        let (mut translator, calls) = translator(&config, "hello", true);
+        let prices = price_estimator("translates");
+
+        let audio = utterance(&config, 2.0);
+        let result = translate_audio(audio, &config, &mut translator, &prices, None).await;
+        assert_eq!(
+            result.unwrap(),
+            Some(("hello".to_string(), "こんにちは".to_string()))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // A free engine and a self-hosted translation server cost nothing
+        assert_eq!(prices.total_cost(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn counts_transcription_cost_when_nothing_is_left_to_translate() {
+        let mut config = test_config("");
+        without_translation_server(&mut config);
+        let (mut translator, _) = translator(&config, "", false);
+        let prices = price_estimator("nothing_left");
+
+        let audio = utterance(&config, 6.0);
+        let result = translate_audio(audio, &config, &mut translator, &prices, None).await;
+        assert!(result.unwrap().is_none());
+        // 6 seconds of Whisper at $0.006 per minute
+        assert!((prices.total_cost() - 0.0006).abs() < 1e-9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partials_skip_instead_of_waiting_for_the_rate_limit() {
+        let mut config = test_config("");
+        config.rate_limit.requests_per_minute = 3;
+        let (mut translator, calls) = translator(&config, "hello", false);
+        let prices = price_estimator("partials_skip");
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        for _ in 0..5 {
+            let audio = utterance(&config, 2.0);
+            process_partial_audio(audio, &config, &mut translator, &socket, &prices)
+                .await
+                .unwrap();
+        }
+        // The last request of the minute is left for the whole utterance
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Runs `process_audio` for an utterance whose translation fails and
+    /// returns how many OSC messages reached the game.
+    async fn messages_sent_on_failed_translation(partials: bool) -> usize {
+        let game = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config(if partials {
+            "[transcription]\npartial_interval_ms = 1000"
+        } else {
+            ""
+        });
+        config.osc.output_port = game.local_addr().unwrap().port();
+        without_translation_server(&mut config);
+        let config = Arc::new(config);
+        let (mut translator, _) = translator(&config, "hello", true);
+        let prices = price_estimator(&format!("failed_translation_{}", partials));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let typing_indicator = TypingIndicator::new(Arc::clone(&socket), Arc::clone(&config));
+
+        let audio = utterance(&config, 2.0);
+        let result = process_audio(
+            audio,
+            &config,
+            &mut translator,
+            &socket,
+            &typing_indicator,
+            &prices,
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+
+        let mut buf = [0u8; 1024];
+        let mut received = 0;
+        while let Ok(Ok(_)) =
+            tokio::time::timeout(Duration::from_millis(100), game.recv_from(&mut buf)).await
+        {
+            received += 1;
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn clears_the_partial_draft_when_translation_fails() {
+        // Only the typing indicator is turned off
+        assert_eq!(messages_sent_on_failed_translation(false).await, 1);
+        // The partial draft is cleared as well
+        assert_eq!(messages_sent_on_failed_translation(true).await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_free_engines_too() {
+        let mut config = test_config("");
+        config.rate_limit.requests_per_minute = 1;
+        without_translation_server(&mut config);
+        let (mut translator, calls) = translator(&config, "", true);
+        let prices = price_estimator("rate_limits");
+
+        let started = tokio::time::Instant::now();
+        for _ in 0..2 {
+            let audio = utterance(&config, 2.0);
+            translate_audio(audio, &config, &mut translator, &prices, None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_secs(59));
+    }
 }
@@ -1,143 +1,781 @@
-use crate::config::Config;
-use crate::types::AudioEvent;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use hound::WavWriter;
+use crate::audio_conditioning::resample;
+use crate::audio_encoding::{AudioEncoder, EncodedAudio};
+use crate::audio_filters::{check_channels, normalize, Preprocessor};
+use crate::audio_source::{AudioSource, MicrophoneSource, SampleSink, StreamFormat};
+use crate::config::{Config, SilenceDuration};
+use crate::event_queue::EventSender;
+use crate::input_levels::InputLevels;
+use crate::types::{AudioEvent, RecorderControls};
+use crate::voice_activity::{create_detector, VoiceActivityDetector};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::collections::VecDeque;
 use std::error::Error;
-use std::io::Cursor;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
-
-struct NoiseGate {
-    threshold: f32,
-    hold_time: f32,
-    last_active: std::time::Instant,
-    is_active: bool,
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Keeps the most recent samples heard while idle so that soft speech onsets
+/// that are still below the gate threshold can be prepended to a recording.
+struct PreRollBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl PreRollBuffer {
+    fn new(capacity: usize) -> Self {
+        PreRollBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, data: &[f32]) {
+        if data.len() >= self.capacity {
+            self.samples.clear();
+            self.samples.extend(&data[data.len() - self.capacity..]);
+            return;
+        }
+
+        let overflow = (self.samples.len() + data.len()).saturating_sub(self.capacity);
+        self.samples.drain(..overflow);
+        self.samples.extend(data);
+    }
+
+    fn drain_into(&mut self, buffer: &mut Vec<f32>) {
+        buffer.extend(self.samples.drain(..));
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Splits incoming float samples into utterances, either with voice activity
+/// detection or push-to-talk, and encodes each one for upload.
+struct Recorder {
+    detector: Box<dyn VoiceActivityDetector>,
+    pre_roll: PreRollBuffer,
+    buffer: Vec<f32>,
+    /// Part of `buffer` where the detector heard voice, used to trim silence.
+    speech_start: Option<usize>,
+    speech_end: usize,
+    /// Samples kept before and after the speech when trimming is enabled.
+    trim_margins: Option<(usize, usize)>,
+    /// Samples in `buffer` that were clipped at the input.
+    clipped_samples: usize,
+    is_recording: bool,
+    silent_samples: usize,
+    silence_samples: usize,
+    legacy_silence_frames: Option<u32>,
+    max_utterance_samples: Option<usize>,
+    /// Buffer growth between partial snapshots, when live transcription is on.
+    partial_interval_samples: Option<usize>,
+    next_partial_at: usize,
+    sample_rate: u32,
+    target_sample_rate: u32,
+    /// Target and peak level in dBFS when normalization is enabled.
+    normalize_levels: Option<(f32, f32)>,
+    encoder: AudioEncoder,
+    push_to_talk: bool,
+    controls: Arc<RecorderControls>,
+    tx: EventSender,
 }
 
-impl NoiseGate {
-    fn new(threshold: f32, hold_time: f32) -> Self {
-        NoiseGate {
-            threshold,
-            hold_time,
-            last_active: std::time::Instant::now(),
-            is_active: false,
+impl Recorder {
+    fn new(
+        config: &Config,
+        sample_rate: u32,
+        controls: Arc<RecorderControls>,
+        tx: EventSender,
+    ) -> Self {
+        // Durations are converted to sample counts so they do not depend on
+        // how many frames the device delivers per callback
+        let ms_to_samples = |ms: u64| (ms * sample_rate as u64 / 1000) as usize;
+
+        if config.audio.noise_gate_hold_ms.is_none() && config.audio.noise_gate_hold_time.is_some()
+        {
+            println!(
+                "Warning: `noise_gate_hold_time` is deprecated, use `noise_gate_hold_ms = {}` instead.",
+                config.audio.noise_gate_hold_duration_ms()
+            );
+        }
+
+        let (silence_samples, legacy_silence_frames) = match config.audio.silence_duration() {
+            SilenceDuration::Millis(ms) => (ms_to_samples(ms), None),
+            SilenceDuration::CallbackFrames(frames) => (0, Some(frames)),
+        };
+
+        let partial_interval_samples = config
+            .transcription
+            .partial_interval_ms
+            .map(|ms| ms_to_samples(ms).max(1));
+
+        Recorder {
+            detector: create_detector(&config.audio, sample_rate, 1),
+            pre_roll: PreRollBuffer::new(ms_to_samples(config.audio.pre_roll_ms)),
+            buffer: Vec::new(),
+            speech_start: None,
+            speech_end: 0,
+            // Never trim into the pre-roll, it is there to catch soft onsets
+            trim_margins: config.audio.trim_silence.then(|| {
+                let margin = ms_to_samples(config.audio.trim_margin_ms);
+                (margin.max(ms_to_samples(config.audio.pre_roll_ms)), margin)
+            }),
+            clipped_samples: 0,
+            is_recording: false,
+            silent_samples: 0,
+            silence_samples,
+            legacy_silence_frames,
+            max_utterance_samples: config
+                .audio
+                .max_utterance_seconds
+                .map(|seconds| (seconds * sample_rate as f32) as usize),
+            partial_interval_samples,
+            next_partial_at: partial_interval_samples.unwrap_or(0),
+            sample_rate,
+            target_sample_rate: config.audio.target_sample_rate,
+            normalize_levels: config.audio.normalize.then_some((
+                config.audio.normalize_target_db,
+                config.audio.normalize_peak_db,
+            )),
+            encoder: AudioEncoder::new(&config.audio),
+            push_to_talk: config.audio.push_to_talk_parameter.is_some(),
+            controls,
+            tx,
+        }
+    }
+
+    fn process(&mut self, data: &[f32]) {
+        if self.controls.muted.load(Ordering::Relaxed) {
+            self.discard_recording();
+            // Nothing heard while muted may end up in the next recording
+            self.pre_roll.clear();
+        } else if self.push_to_talk {
+            self.process_push_to_talk(data);
+        } else {
+            self.process_voice_activity(data);
+        }
+
+        if self.is_recording {
+            self.emit_partial();
+        }
+    }
+
+    fn process_voice_activity(&mut self, data: &[f32]) {
+        if self.detector.process(data) {
+            if !self.is_recording {
+                println!("Sound detected. Starting recording...");
+                self.start_recording();
+            }
+
+            self.buffer.extend_from_slice(data);
+            self.mark_speech(data.len());
+            self.silent_samples = 0;
+            self.split_long_utterance();
+        } else if self.is_recording {
+            self.silent_samples += data.len();
+
+            if self.silent_samples >= self.silence_samples {
+                println!("Silence detected. Stopping recording and processing audio...");
+                self.stop_recording();
+            } else {
+                // Keep recording during short pauses
+                self.buffer.extend_from_slice(data);
+                self.split_long_utterance();
+            }
+        } else {
+            self.pre_roll.push(data);
+        }
+    }
+
+    /// Records exactly while the push-to-talk avatar parameter is held and
+    /// processes the recording as soon as it is released.
+    fn process_push_to_talk(&mut self, data: &[f32]) {
+        // Still run the detector so silence around the speech can be trimmed
+        let voice = self.detector.process(data);
+
+        if self.controls.push_to_talk.load(Ordering::Relaxed) {
+            if !self.is_recording {
+                println!("Push-to-talk pressed. Starting recording...");
+                self.start_recording();
+            }
+
+            self.buffer.extend_from_slice(data);
+            if voice {
+                self.mark_speech(data.len());
+            }
+            self.split_long_utterance();
+        } else if self.is_recording {
+            println!("Push-to-talk released. Processing audio...");
+            self.stop_recording();
+        } else {
+            self.pre_roll.push(data);
+        }
+    }
+
+    fn start_recording(&mut self) {
+        self.is_recording = true;
+        self.tx.send(AudioEvent::StartRecording);
+        self.pre_roll.drain_into(&mut self.buffer);
+    }
+
+    /// Counts clipped samples from the block that was just processed.
+    fn add_clipped_samples(&mut self, count: usize) {
+        if self.is_recording {
+            self.clipped_samples += count;
+        }
+    }
+
+    /// Records that the last `len` samples appended to `buffer` passed the
+    /// detector. The part it only held open after the voice stopped is
+    /// silence, so it is not counted as speech.
+    fn mark_speech(&mut self, len: usize) {
+        let held = self.detector.samples_since_voice();
+        if held >= len {
+            return;
+        }
+        self.speech_start.get_or_insert(self.buffer.len() - len);
+        self.speech_end = self.buffer.len() - held;
+    }
+
+    fn stop_recording(&mut self) {
+        self.is_recording = false;
+        self.silent_samples = 0;
+
+        if !self.buffer.is_empty() {
+            self.emit_buffer();
+        }
+
+        self.tx.send(AudioEvent::StopRecording);
+    }
+
+    /// Processes whatever is still being recorded once the source has ended.
+    fn finish(&mut self) {
+        if self.is_recording {
+            println!("End of audio source. Processing audio...");
+            self.stop_recording();
+        }
+    }
+
+    /// Drops a partial recording without processing it, e.g. when muted in-game.
+    fn discard_recording(&mut self) {
+        if self.is_recording {
+            println!("Discarding partial recording.");
+            self.is_recording = false;
+            self.silent_samples = 0;
+            self.clear_buffer();
+            self.tx.send(AudioEvent::StopRecording);
+        }
+    }
+
+    /// Converts the deprecated callback-count `silence_threshold` using the
+    /// size of the device callbacks, which is what it effectively measured.
+    fn resolve_legacy_silence(&mut self, callback_len: usize) {
+        let frames = match self.legacy_silence_frames.take() {
+            Some(frames) => frames,
+            None => return,
+        };
+        self.silence_samples = frames as usize * callback_len;
+
+        let samples_per_ms = self.sample_rate as usize / 1000;
+        let silence_ms = self.silence_samples / samples_per_ms.max(1);
+        println!(
+            "Warning: `silence_threshold` is deprecated because its duration depends on the audio device. \
+             On this device it is about {} ms, use `silence_ms = {}` instead.",
+            silence_ms, silence_ms
+        );
+    }
+
+    /// Emits the buffered audio early once it reaches the maximum utterance
+    /// length, cutting at the quietest point of the last second and keeping
+    /// the rest as the start of the next segment.
+    fn split_long_utterance(&mut self) {
+        let max_samples = match self.max_utterance_samples {
+            Some(max_samples) => max_samples,
+            None => return,
+        };
+        if self.buffer.len() < max_samples {
+            return;
+        }
+
+        let split_at = find_quietest_split(&self.buffer, self.sample_rate);
+        let remainder = self.buffer.split_off(split_at);
+        let remainder_speech_end = self.speech_end.saturating_sub(split_at);
+        self.speech_end = self.speech_end.min(split_at);
+
+        println!("Maximum utterance length reached. Processing audio so far...");
+        self.emit_buffer();
+        self.buffer = remainder;
+        if remainder_speech_end > 0 {
+            self.speech_start = Some(0);
+            self.speech_end = remainder_speech_end;
+        }
+    }
+
+    /// The part of `buffer` to upload: the speech plus margins when trimming
+    /// is enabled, otherwise everything.
+    fn upload_range(&self) -> std::ops::Range<usize> {
+        match (self.trim_margins, self.speech_start) {
+            (Some((leading, trailing)), Some(start)) if start < self.speech_end => {
+                start.saturating_sub(leading)..(self.speech_end + trailing).min(self.buffer.len())
+            }
+            _ => 0..self.buffer.len(),
+        }
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+        self.speech_start = None;
+        self.speech_end = 0;
+        self.clipped_samples = 0;
+        self.next_partial_at = self.partial_interval_samples.unwrap_or(0);
+    }
+
+    /// Resamples, normalizes and encodes the upload range of the buffer.
+    fn encode_buffer(&self) -> Result<EncodedAudio, Box<dyn Error>> {
+        let samples = &self.buffer[self.upload_range()];
+        // Clipping is counted per block rather than per sample position, so
+        // it is compared with everything recorded, not just the trimmed range
+        let clipped_ratio =
+            self.clipped_samples.min(self.buffer.len()) as f32 / self.buffer.len().max(1) as f32;
+        let levels = InputLevels::measure(samples, clipped_ratio, self.sample_rate);
+
+        let mut samples = resample(samples, self.sample_rate, self.target_sample_rate)?;
+        if let Some((target_db, peak_db)) = self.normalize_levels {
+            normalize(&mut samples, self.target_sample_rate, target_db, peak_db);
         }
+        let mut audio = self.encoder.encode(&samples, self.target_sample_rate)?;
+        audio.levels = Some(levels);
+        Ok(audio)
     }
 
-    fn process(&mut self, samples: &[f32]) -> bool {
-        let max_amplitude = samples.iter().map(|&s| s.abs()).fold(0.0f32, f32::max);
+    fn emit_buffer(&mut self) {
+        match self.encode_buffer() {
+            Ok(audio) => {
+                self.tx.send(AudioEvent::AudioData(audio));
+            }
+            Err(e) => eprintln!("Error preparing audio for upload: {}", e),
+        }
+        self.clear_buffer();
+    }
 
-        if max_amplitude > self.threshold {
-            self.last_active = std::time::Instant::now();
-            self.is_active = true;
-        } else if self.is_active && self.last_active.elapsed().as_secs_f32() > self.hold_time {
-            self.is_active = false;
+    /// Sends a snapshot of the utterance so far each time the buffer has
+    /// grown by another partial interval.
+    fn emit_partial(&mut self) {
+        let interval = match self.partial_interval_samples {
+            Some(interval) => interval,
+            None => return,
+        };
+        if self.buffer.len() < self.next_partial_at {
+            return;
         }
+        self.next_partial_at = self.buffer.len() + interval;
 
-        self.is_active
+        match self.encode_buffer() {
+            Ok(audio) => self.tx.send(AudioEvent::PartialAudio(audio)),
+            Err(e) => eprintln!("Error preparing partial audio: {}", e),
+        }
     }
 }
 
+/// Returns the sample index at the centre of the lowest-energy 20 ms window
+/// within the last second of `buffer`.
+fn find_quietest_split(buffer: &[f32], sample_rate: u32) -> usize {
+    let window_len = (sample_rate as usize / 50).max(1);
+    let search_len = (sample_rate as usize).min(buffer.len());
+    let search_start = buffer.len() - search_len;
+
+    let (quietest_offset, _) = buffer[search_start..]
+        .chunks(window_len)
+        .enumerate()
+        .map(|(i, window)| {
+            let energy = window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32;
+            (i * window_len + window.len() / 2, energy)
+        })
+        .fold((search_len, f32::MAX), |quietest, candidate| {
+            if candidate.1 < quietest.1 {
+                candidate
+            } else {
+                quietest
+            }
+        });
+
+    search_start + quietest_offset
+}
+
+/// Runs `source` until it ends, turning its samples into `AudioEvent`s.
+///
+/// The source only pushes samples into a lock-free ring buffer. Filtering,
+/// gating, segmentation and encoding happen on a separate worker thread so that
+/// nothing on the audio callback can block, allocate a WAV or panic.
 pub fn start_audio_recording(
     config: &Config,
-    tx: mpsc::Sender<AudioEvent>,
+    source: &mut dyn AudioSource,
+    controls: Arc<RecorderControls>,
+    tx: EventSender,
 ) -> Result<(), Box<dyn Error>> {
-    let host = cpal::default_host();
-    let device = host
-        .default_input_device()
-        .expect("No input device available");
-    let device_config = device.default_input_config()?;
+    let format = source.format();
+    let recorder = Recorder::new(config, format.sample_rate, controls, tx);
+    let preprocessor = Preprocessor::new(&config.audio, format.sample_rate, format.channels)?;
 
-    let sample_rate = device_config.sample_rate().0 as f32;
-    let channels = device_config.channels() as usize;
-    let sample_format = device_config.sample_format();
+    let capacity = RING_BUFFER_SECONDS * format.sample_rate as usize * format.channels as usize;
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let stats = Arc::new(SinkStats::default());
 
-    let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+    let worker_stats = Arc::clone(&stats);
+    let worker = std::thread::Builder::new()
+        .name("recorder".to_string())
+        .spawn(move || {
+            run_recorder_worker(recorder, preprocessor, consumer, worker_stats, format)
+        })?;
 
-    let stream = match sample_format {
-        cpal::SampleFormat::F32 => {
-            let audio_data = Arc::new(Mutex::new(Vec::new()));
-            let audio_data_clone = Arc::clone(&audio_data);
+    let sink = RingBufferSink {
+        producer,
+        drop_when_full: source.is_live(),
+        stats,
+    };
+    let result = source.run(Box::new(sink));
+
+    // The sink has been dropped by now, so the worker drains what is left and exits
+    if worker.join().is_err() {
+        eprintln!("The audio recorder worker stopped unexpectedly");
+    }
+
+    result
+}
+
+/// Seconds of audio the ring buffer can hold before a live source drops samples.
+const RING_BUFFER_SECONDS: usize = 2;
+/// How often the worker checks the ring buffer for new samples.
+const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Counters shared between the audio callback and the recorder worker.
+#[derive(Default)]
+struct SinkStats {
+    dropped_samples: AtomicUsize,
+    callback_len: AtomicUsize,
+}
+
+/// Pushes samples from the source into the ring buffer without locking.
+struct RingBufferSink {
+    producer: Producer<f32>,
+    drop_when_full: bool,
+    stats: Arc<SinkStats>,
+}
+
+impl SampleSink for RingBufferSink {
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.stats
+            .callback_len
+            .store(samples.len(), Ordering::Relaxed);
+
+        let mut remaining = samples;
+        loop {
+            let writable = remaining.len().min(self.producer.slots());
+            if let Ok(chunk) = self.producer.write_chunk_uninit(writable) {
+                chunk.fill_from_iter(remaining[..writable].iter().copied());
+                remaining = &remaining[writable..];
+            }
+
+            if remaining.is_empty() || self.producer.is_abandoned() {
+                return;
+            }
+            if self.drop_when_full {
+                self.stats
+                    .dropped_samples
+                    .fetch_add(remaining.len(), Ordering::Relaxed);
+                return;
+            }
+            std::thread::sleep(WORKER_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Feeds the recorder from the ring buffer in 10 ms blocks until the sink
+/// is dropped and every remaining sample has been processed.
+fn run_recorder_worker(
+    mut recorder: Recorder,
+    mut preprocessor: Preprocessor,
+    mut consumer: Consumer<f32>,
+    stats: Arc<SinkStats>,
+    format: StreamFormat,
+) {
+    let block_len = (format.sample_rate as usize / 100).max(1) * format.channels as usize;
+    let mut block = Vec::with_capacity(block_len);
+
+    loop {
+        let available = consumer.slots();
+        if available == 0 {
+            // Re-check emptiness after abandonment so a final push is not lost
+            if consumer.is_abandoned() && consumer.is_empty() {
+                break;
+            }
+            std::thread::sleep(WORKER_POLL_INTERVAL);
+            continue;
+        }
+
+        let callback_len = stats.callback_len.load(Ordering::Relaxed);
+        recorder.resolve_legacy_silence(callback_len / format.channels.max(1) as usize);
 
-            let tx_clone = tx.clone();
+        let readable = available.min(block_len);
+        if let Ok(chunk) = consumer.read_chunk(readable) {
+            let (first, second) = chunk.as_slices();
+            block.clear();
+            block.extend_from_slice(first);
+            block.extend_from_slice(second);
+            chunk.commit_all();
 
-            let mut noise_gate = NoiseGate::new(
-                config.audio.noise_gate_threshold,
-                config.audio.noise_gate_hold_time,
+            // The noise profile is only learned from what the recorder ignores
+            let samples = preprocessor.process(&block, !recorder.is_recording);
+            if !samples.is_empty() {
+                recorder.process(&samples);
+            }
+            recorder.add_clipped_samples(preprocessor.take_clipped_frames());
+        }
+
+        let dropped = stats.dropped_samples.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            eprintln!(
+                "Warning: the audio recorder fell behind and dropped {} samples",
+                dropped
             );
+        }
+    }
 
-            let mut is_recording = false;
-            let mut silent_frames = 0;
-            let silence_threshold = config.audio.silence_threshold;
-
-            device.build_input_stream(
-                &device_config.into(),
-                move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    if noise_gate.process(data) {
-                        let mut buffer = audio_data_clone.lock().unwrap();
-
-                        if !is_recording {
-                            is_recording = true;
-                            println!("Sound detected. Starting recording...");
-                            let _ = tx_clone.try_send(AudioEvent::StartRecording);
-                        }
-
-                        buffer.extend_from_slice(data);
-                        silent_frames = 0;
-                    } else if is_recording {
-                        silent_frames += 1;
-
-                        if silent_frames >= silence_threshold {
-                            is_recording = false;
-                            silent_frames = 0;
-
-                            let mut buffer = audio_data_clone.lock().unwrap();
-                            if !buffer.is_empty() {
-                                println!(
-                                    "Silence detected. Stopping recording and processing audio..."
-                                );
-                                let mut wav_buffer = Vec::new();
-                                {
-                                    let mut writer = WavWriter::new(
-                                        Cursor::new(&mut wav_buffer),
-                                        hound::WavSpec {
-                                            channels: channels as u16,
-                                            sample_rate: sample_rate as u32,
-                                            bits_per_sample: 32,
-                                            sample_format: hound::SampleFormat::Float,
-                                        },
-                                    )
-                                    .unwrap();
-
-                                    for &sample in buffer.iter() {
-                                        writer.write_sample(sample).unwrap();
-                                    }
-                                    writer.finalize().unwrap();
-                                }
-
-                                let _ = tx_clone.try_send(AudioEvent::AudioData(wav_buffer));
-                                buffer.clear();
-                            }
-
-                            let _ = tx_clone.try_send(AudioEvent::StopRecording);
-                        } else {
-                            // Keep recording during short pauses
-                            let mut buffer = audio_data_clone.lock().unwrap();
-                            buffer.extend_from_slice(data);
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            )?
-        }
-        _ => return Err("Unsupported sample format".into()),
-    };
+    recorder.finish();
+}
+
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A stream that ran at least this long resets the reconnect backoff.
+const RECONNECT_STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// Records from the configured microphone for as long as the app runs,
+/// reopening the device with exponential backoff whenever it goes away.
+///
+/// Only returns an error for settings that do not fit the device, which are
+/// checked the first time it opens and would fail the same way on every retry.
+pub fn record_microphone(
+    config: &Config,
+    controls: Arc<RecorderControls>,
+    tx: EventSender,
+) -> Result<(), Box<dyn Error>> {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    let mut checked_settings = false;
 
-    stream.play()?;
+    while !tx.is_closed() {
+        match MicrophoneSource::open(&config.audio) {
+            Ok(mut source) => {
+                if !checked_settings {
+                    check_channels(&config.audio, source.format().channels)?;
+                    checked_settings = true;
+                }
+                tx.send(AudioEvent::DeviceConnected(source.name().to_string()));
 
-    // Keep the stream alive
-    std::mem::forget(stream);
+                let started = Instant::now();
+                let reason = match start_audio_recording(
+                    config,
+                    &mut source,
+                    Arc::clone(&controls),
+                    tx.clone(),
+                ) {
+                    Ok(()) => "The audio stream ended".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                tx.send(AudioEvent::DeviceLost(reason));
+
+                if started.elapsed() >= RECONNECT_STABLE_AFTER {
+                    backoff = RECONNECT_INITIAL_BACKOFF;
+                }
+            }
+            Err(e) => eprintln!("Error opening audio input device: {}", e),
+        }
+
+        println!("Retrying audio input in {} second(s)...", backoff.as_secs());
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_queue::{event_queue, EventReceiver, OverflowPolicy};
+    use crate::test_support::test_config;
+
+    const SAMPLE_RATE: u32 = 16000;
+    /// 10 ms, the block size the recorder worker uses.
+    const BLOCK: usize = SAMPLE_RATE as usize / 100;
+
+    /// A recorder on a 16 kHz mono stream with a 500 ms silence timeout.
+    fn recorder(extra: &str) -> (Recorder, EventReceiver) {
+        let config = test_config(&format!("silence_ms = 500\n{}", extra));
+        let (tx, rx) = event_queue(10, OverflowPolicy::Merge, AudioEncoder::new(&config.audio));
+        let controls = Arc::new(RecorderControls::default());
+        (Recorder::new(&config, SAMPLE_RATE, controls, tx), rx)
+    }
+
+    /// Feeds `seconds` of a constant level in 10 ms blocks.
+    fn feed(recorder: &mut Recorder, level: f32, seconds: f32) {
+        let block = vec![level; BLOCK];
+        for _ in 0..(seconds * 100.0).round() as usize {
+            recorder.process(&block);
+        }
+    }
+
+    /// Everything queued so far, without waiting for more.
+    async fn drain(rx: &mut EventReceiver) -> Vec<AudioEvent> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::ZERO, rx.recv()).await {
+            events.push(event);
+        }
+        events
+    }
+
+    /// Lengths in milliseconds of the partial snapshots among `events`.
+    fn partial_lengths(events: &[AudioEvent]) -> Vec<u128> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                AudioEvent::PartialAudio(audio) => Some(audio.duration.as_millis()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lengths in milliseconds of the utterances among `events`.
+    fn audio_lengths(events: &[AudioEvent]) -> Vec<u128> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                AudioEvent::AudioData(audio) => Some(audio.duration.as_millis()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 100 ms of pre-roll and trim margin, and a 300 ms hold.
+    const TRIMMED: &str = "pre_roll_ms = 100\ntrim_margin_ms = 100\nnoise_gate_hold_ms = 300";
+
+    #[tokio::test]
+    async fn trims_to_the_speech_plus_margins_without_the_hold_time() {
+        let (mut recorder, mut rx) = recorder(TRIMMED);
+        feed(&mut recorder, 0.0, 0.5);
+        feed(&mut recorder, 0.5, 1.0);
+        feed(&mut recorder, 0.0, 1.0);
+
+        // 100 ms of pre-roll, 1 s of speech and a 100 ms margin
+        assert_eq!(audio_lengths(&drain(&mut rx).await), vec![1200]);
+    }
+
+    #[tokio::test]
+    async fn keeps_pauses_between_words() {
+        let (mut recorder, mut rx) = recorder(TRIMMED);
+        feed(&mut recorder, 0.0, 0.5);
+        feed(&mut recorder, 0.5, 0.5);
+        feed(&mut recorder, 0.0, 0.2);
+        feed(&mut recorder, 0.5, 0.5);
+        feed(&mut recorder, 0.0, 1.0);
+
+        assert_eq!(audio_lengths(&drain(&mut rx).await), vec![1400]);
+    }
+
+    #[tokio::test]
+    async fn uploads_everything_without_trimming() {
+        let (mut recorder, mut rx) = recorder(&format!("{}\ntrim_silence = false", TRIMMED));
+        feed(&mut recorder, 0.0, 0.5);
+        feed(&mut recorder, 0.5, 1.0);
+        feed(&mut recorder, 0.0, 1.0);
+
+        // Pre-roll, speech, the hold time and the silence that ended the recording
+        assert_eq!(audio_lengths(&drain(&mut rx).await), vec![1890]);
+    }
+
+    /// `seconds` of a constant level with 20 ms of silence starting at `gap`.
+    fn with_gap(seconds: f32, gap: f32) -> Vec<f32> {
+        let mut samples = vec![0.5; (seconds * SAMPLE_RATE as f32) as usize];
+        let start = (gap * SAMPLE_RATE as f32) as usize;
+        samples[start..start + 320].fill(0.0);
+        samples
+    }
+
+    #[test]
+    fn splits_in_the_middle_of_the_quietest_window_of_the_last_second() {
+        let buffer = with_gap(3.0, 2.5);
+        assert_eq!(find_quietest_split(&buffer, SAMPLE_RATE), 40000 + 160);
+    }
+
+    #[test]
+    fn ignores_pauses_before_the_last_second() {
+        let buffer = with_gap(3.0, 0.5);
+        // Without a quieter window the first one of the last second wins
+        assert_eq!(find_quietest_split(&buffer, SAMPLE_RATE), 32000 + 160);
+    }
+
+    #[test]
+    fn searches_all_of_a_buffer_shorter_than_a_second() {
+        let buffer = with_gap(0.5, 0.1);
+        assert_eq!(find_quietest_split(&buffer, SAMPLE_RATE), 1600 + 160);
+        assert_eq!(find_quietest_split(&[], SAMPLE_RATE), 0);
+    }
+
+    #[tokio::test]
+    async fn splits_long_utterances_at_the_pause() {
+        let (mut recorder, mut rx) = recorder("trim_silence = false\nmax_utterance_seconds = 2.0");
+        feed(&mut recorder, 0.5, 1.5);
+        feed(&mut recorder, 0.0, 0.02);
+        feed(&mut recorder, 0.5, 0.6);
+
+        // Cut in the middle of the pause, the rest starts the next segment
+        assert_eq!(audio_lengths(&drain(&mut rx).await), vec![1510]);
+        assert_eq!(recorder.buffer.len(), (0.61 * SAMPLE_RATE as f32) as usize);
+    }
+
+    #[tokio::test]
+    async fn emits_a_partial_each_time_the_buffer_grows_by_the_interval() {
+        let (mut recorder, mut rx) =
+            recorder("trim_silence = false\n[transcription]\npartial_interval_ms = 1000");
+
+        let mut partials = Vec::new();
+        for _ in 0..35 {
+            feed(&mut recorder, 0.5, 0.1);
+            // Drained as the main loop would, so no snapshot replaces another
+            partials.extend(partial_lengths(&drain(&mut rx).await));
+        }
+        assert_eq!(partials, vec![1000, 2000, 3000]);
+
+        // The next utterance starts counting from zero again
+        feed(&mut recorder, 0.0, 1.0);
+        drain(&mut rx).await;
+        feed(&mut recorder, 0.5, 1.5);
+        assert_eq!(partial_lengths(&drain(&mut rx).await), vec![1000]);
+    }
+
+    #[tokio::test]
+    async fn emits_no_partials_unless_enabled() {
+        let (mut recorder, mut rx) = recorder("trim_silence = false");
+        feed(&mut recorder, 0.5, 3.0);
+        assert!(partial_lengths(&drain(&mut rx).await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_the_waiting_partial_once_the_utterance_is_complete() {
+        let (mut recorder, mut rx) =
+            recorder("trim_silence = false\n[transcription]\npartial_interval_ms = 1000");
+        feed(&mut recorder, 0.5, 2.5);
+        feed(&mut recorder, 0.0, 1.0);
+
+        let events = drain(&mut rx).await;
+        assert!(partial_lengths(&events).is_empty());
+        assert!(matches!(
+            events.as_slice(),
+            [
+                AudioEvent::StartRecording,
+                AudioEvent::AudioData(_),
+                AudioEvent::StopRecording
+            ]
+        ));
+    }
+}
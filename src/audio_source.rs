@@ -0,0 +1,371 @@
+use crate::audio_devices::open_input_device;
+use crate::config::{AudioConfig, SilenceDuration};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// How long a live stream may go without delivering audio before the device
+/// is considered lost. Some backends never report an unplugged device.
+const STREAM_WATCHDOG_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Channel count and sample rate of the interleaved samples a source produces.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// Receives interleaved float samples from an [`AudioSource`]. Dropping the
+/// sink marks the end of the stream.
+pub trait SampleSink: Send + 'static {
+    fn push_samples(&mut self, samples: &[f32]);
+}
+
+/// Something that produces audio for the recorder, such as a microphone or
+/// a set of saved recordings.
+pub trait AudioSource {
+    fn format(&self) -> StreamFormat;
+
+    /// Live sources deliver samples from a real-time callback and cannot
+    /// wait for the sink to catch up.
+    fn is_live(&self) -> bool;
+
+    /// Delivers samples to `sink`, blocking the calling thread until the
+    /// source is exhausted or fails.
+    ///
+    /// Live sources only return once the stream has failed.
+    fn run(&mut self, sink: Box<dyn SampleSink>) -> Result<(), Box<dyn Error>>;
+}
+
+/// Live capture from the configured cpal input device.
+pub struct MicrophoneSource {
+    device: cpal::Device,
+    name: String,
+    stream_config: cpal::StreamConfig,
+    sample_format: cpal::SampleFormat,
+}
+
+impl MicrophoneSource {
+    pub fn open(config: &AudioConfig) -> Result<Self, Box<dyn Error>> {
+        let device = open_input_device(config)?;
+        let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+        let device_config = device.default_input_config()?;
+
+        Ok(MicrophoneSource {
+            device,
+            name,
+            sample_format: device_config.sample_format(),
+            stream_config: device_config.into(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AudioSource for MicrophoneSource {
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            channels: self.stream_config.channels,
+            sample_rate: self.stream_config.sample_rate.0,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn run(&mut self, sink: Box<dyn SampleSink>) -> Result<(), Box<dyn Error>> {
+        let device = &self.device;
+        let config = &self.stream_config;
+        let (error_tx, error_rx) = mpsc::channel();
+        let monitor = StreamMonitor {
+            error_tx,
+            received_audio: Arc::new(AtomicBool::new(false)),
+        };
+        let received_audio = Arc::clone(&monitor.received_audio);
+
+        let stream = match self.sample_format {
+            cpal::SampleFormat::F32 => build_input_stream::<f32>(device, config, sink, monitor)?,
+            cpal::SampleFormat::F64 => build_input_stream::<f64>(device, config, sink, monitor)?,
+            cpal::SampleFormat::I16 => build_input_stream::<i16>(device, config, sink, monitor)?,
+            cpal::SampleFormat::I32 => build_input_stream::<i32>(device, config, sink, monitor)?,
+            cpal::SampleFormat::U16 => build_input_stream::<u16>(device, config, sink, monitor)?,
+            sample_format => {
+                return Err(format!("Unsupported sample format: {}", sample_format).into())
+            }
+        };
+
+        stream.play()?;
+
+        // The stream stops when it is dropped, so it lives here until it fails
+        loop {
+            match error_rx.recv_timeout(STREAM_WATCHDOG_INTERVAL) {
+                Ok(cpal::StreamError::DeviceNotAvailable) => {
+                    return Err("The input device is no longer available".into())
+                }
+                Ok(err) => eprintln!("An error occurred on the audio stream: {}", err),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if !received_audio.swap(false, Ordering::Relaxed) {
+                        return Err("The input device stopped delivering audio".into());
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err("The audio stream closed unexpectedly".into())
+                }
+            }
+        }
+    }
+}
+
+/// Lets the stream callbacks report back to the thread that owns the stream.
+struct StreamMonitor {
+    error_tx: mpsc::Sender<cpal::StreamError>,
+    received_audio: Arc<AtomicBool>,
+}
+
+/// Builds an input stream for the device's native sample type, converting
+/// every callback buffer to `f32` before it reaches the sink.
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    stream_config: &cpal::StreamConfig,
+    mut sink: Box<dyn SampleSink>,
+    monitor: StreamMonitor,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let StreamMonitor {
+        error_tx,
+        received_audio,
+    } = monitor;
+    let err_fn = move |err| {
+        let _ = error_tx.send(err);
+    };
+    let mut samples = Vec::new();
+
+    device.build_input_stream(
+        stream_config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            received_audio.store(true, Ordering::Relaxed);
+            samples.clear();
+            samples.extend(data.iter().map(|&s| s.to_sample::<f32>()));
+            sink.push_samples(&samples);
+        },
+        err_fn,
+        None,
+    )
+}
+
+/// Replays a WAV file, or every WAV file in a directory in name order, as if
+/// it were coming from a microphone.
+///
+/// A `speed` of 1.0 replays in real time, 2.0 twice as fast, and 0 or less
+/// as fast as possible. Each file is followed by enough silence to end the
+/// utterance, so files map onto utterances the same way pauses do live.
+pub struct WavReplaySource {
+    files: Vec<PathBuf>,
+    format: StreamFormat,
+    speed: f32,
+    gap: Duration,
+}
+
+impl WavReplaySource {
+    pub fn open(path: &Path, speed: f32, config: &AudioConfig) -> Result<Self, Box<dyn Error>> {
+        let files = if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+                })
+                .collect();
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        let first = files
+            .first()
+            .ok_or_else(|| format!("No WAV files found in {}", path.display()))?;
+        let spec = hound::WavReader::open(first)?.spec();
+
+        let silence_ms = match config.silence_duration() {
+            SilenceDuration::Millis(ms) => ms,
+            SilenceDuration::CallbackFrames(_) => 2000,
+        };
+        let gap_ms = silence_ms + config.noise_gate_hold_duration_ms() + 250;
+
+        Ok(WavReplaySource {
+            files,
+            format: StreamFormat {
+                channels: spec.channels,
+                sample_rate: spec.sample_rate,
+            },
+            speed,
+            gap: Duration::from_millis(gap_ms),
+        })
+    }
+}
+
+impl AudioSource for WavReplaySource {
+    fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn run(&mut self, mut sink: Box<dyn SampleSink>) -> Result<(), Box<dyn Error>> {
+        let channels = self.format.channels as usize;
+        let sample_rate = self.format.sample_rate as usize;
+        // 10 ms blocks, similar to what a sound card delivers
+        let block_len = (sample_rate / 100).max(1) * channels;
+        let gap = vec![0.0f32; (self.gap.as_millis() as usize * sample_rate / 1000) * channels];
+
+        let started = Instant::now();
+        let mut samples_sent = 0usize;
+
+        for file in &self.files {
+            let reader = hound::WavReader::open(file)?;
+            let spec = reader.spec();
+            if spec.channels != self.format.channels || spec.sample_rate != self.format.sample_rate
+            {
+                eprintln!(
+                    "Skipping {}: expected {} channel(s) at {} Hz",
+                    file.display(),
+                    self.format.channels,
+                    self.format.sample_rate
+                );
+                continue;
+            }
+
+            println!("Replaying {}", file.display());
+            let samples = read_wav_samples(reader)?;
+
+            for block in samples.chunks(block_len).chain(gap.chunks(block_len)) {
+                sink.push_samples(block);
+                samples_sent += block.len();
+
+                if self.speed > 0.0 {
+                    let frames_sent = (samples_sent / channels) as f64;
+                    let due = Duration::from_secs_f64(
+                        frames_sent / sample_rate as f64 / self.speed as f64,
+                    );
+                    if let Some(wait) = due.checked_sub(started.elapsed()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_wav_samples<R: std::io::Read>(
+    reader: hound::WavReader<R>,
+) -> Result<Vec<f32>, hound::Error> {
+    let spec = reader.spec();
+
+    match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 * scale))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_encoding::AudioEncoder;
+    use crate::audio_recording::start_audio_recording;
+    use crate::event_queue::{event_queue, OverflowPolicy};
+    use crate::test_support::{temp_dir, test_config};
+    use crate::types::{AudioEvent, RecorderControls};
+    use std::f32::consts::PI;
+
+    /// Writes a 16-bit mono WAV file of silence with a 440 Hz tone from
+    /// `tone_start` to `tone_end` seconds.
+    fn write_wav(path: &Path, sample_rate: u32, seconds: f32, tone_start: f32, tone_end: f32) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..(seconds * sample_rate as f32) as usize {
+            let t = i as f32 / sample_rate as f32;
+            let sample = if (tone_start..tone_end).contains(&t) {
+                0.5 * (2.0 * PI * 440.0 * t).sin()
+            } else {
+                0.0
+            };
+            writer
+                .write_sample((sample * i16::MAX as f32) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_each_file_as_an_utterance() {
+        let dir = temp_dir("replay");
+        write_wav(&dir.join("01.wav"), 16000, 2.0, 0.5, 1.5);
+        write_wav(&dir.join("02.WAV"), 16000, 0.5, 0.0, 0.5);
+        // Not a WAV file, and a WAV file at another rate
+        fs::write(dir.join("notes.txt"), "not audio").unwrap();
+        write_wav(&dir.join("03.wav"), 48000, 1.0, 0.0, 1.0);
+
+        let config = test_config(
+            "silence_ms = 500\npre_roll_ms = 100\ntrim_margin_ms = 100\nnoise_gate_hold_ms = 300",
+        );
+        let (tx, mut rx) = event_queue(10, OverflowPolicy::Merge, AudioEncoder::new(&config.audio));
+        let mut source = WavReplaySource::open(&dir, 0.0, &config.audio).unwrap();
+
+        let started = Instant::now();
+        start_audio_recording(
+            &config,
+            &mut source,
+            Arc::new(RecorderControls::default()),
+            tx,
+        )
+        .unwrap();
+        // Speed 0 does not wait for real time
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let mut events = Vec::new();
+        let mut lengths = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(match event {
+                AudioEvent::StartRecording => "start",
+                AudioEvent::StopRecording => "stop",
+                AudioEvent::AudioData(audio) => {
+                    lengths.push(audio.duration.as_millis());
+                    "audio"
+                }
+                _ => "other",
+            });
+        }
+        assert_eq!(events, ["start", "audio", "stop", "start", "audio", "stop"]);
+        // Each tone plus the pre-roll and trim margin around it, give or take
+        // a block for the high-pass filter ringing on after the tone
+        assert!((1200..=1210).contains(&lengths[0]), "{:?}", lengths);
+        assert!((700..=710).contains(&lengths[1]), "{:?}", lengths);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::audio_filters::Preprocessor;
+use crate::audio_source::{AudioSource, MicrophoneSource, SampleSink};
+use crate::config::{AudioConfig, VadKind};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
+use toml_edit::{DocumentMut, Item, Value};
+
+const ROOM_TONE_SECONDS: f32 = 5.0;
+const SPEECH_SECONDS: f32 = 8.0;
+/// Levels are measured over 10 ms blocks, the same size the recorder uses.
+const BLOCK_MS: u32 = 10;
+const METER_INTERVAL_BLOCKS: usize = 10;
+const METER_WIDTH: usize = 30;
+const METER_FLOOR_DB: f32 = -60.0;
+/// Pauses longer than this are treated as the end of a sentence, not a dip
+/// between words, when recommending the hold time.
+const MAX_WORD_GAP_MS: u32 = 500;
+
+/// Level of one block of mono audio.
+#[derive(Clone, Copy)]
+struct BlockLevel {
+    peak: f32,
+    rms: f32,
+}
+
+/// Walks the user through recording room tone and speech, recommends
+/// `noise_gate_threshold` and `noise_gate_hold_ms`, and offers to save them
+/// to the config file.
+pub fn run_calibration(config_path: &str, config: &AudioConfig) -> Result<(), Box<dyn Error>> {
+    let mut source = MicrophoneSource::open(config)?;
+    let format = source.format();
+    println!("Calibrating input device: {}", source.name());
+
+    let (producer, mut consumer) =
+        RingBuffer::new(format.sample_rate as usize * format.channels as usize);
+    std::thread::Builder::new()
+        .name("calibration".to_string())
+        .spawn(move || {
+            if let Err(e) = source.run(Box::new(CaptureSink { producer })) {
+                eprintln!("\nAudio input failed: {}", e);
+            }
+        })?;
+
+    // Measure what the recorder's gate will see, including any enabled filters
+    let mut preprocessor = Preprocessor::new(config, format.sample_rate, format.channels)?;
+    let block_len = (format.sample_rate * BLOCK_MS / 1000) as usize;
+
+    println!();
+    println!(
+        "Step 1: stay quiet for {} seconds so the background noise can be measured.",
+        ROOM_TONE_SECONDS
+    );
+    wait_for_enter()?;
+    let room_tone = record_levels(
+        &mut consumer,
+        &mut preprocessor,
+        block_len,
+        ROOM_TONE_SECONDS,
+        true,
+    )?;
+
+    println!();
+    println!(
+        "Step 2: talk normally for {} seconds, as you would in VRChat.",
+        SPEECH_SECONDS
+    );
+    wait_for_enter()?;
+    let speech = record_levels(
+        &mut consumer,
+        &mut preprocessor,
+        block_len,
+        SPEECH_SECONDS,
+        false,
+    )?;
+
+    let noise_peak = percentile(room_tone.iter().map(|level| level.peak), 0.99);
+    let speech_blocks: Vec<f32> = speech
+        .iter()
+        .map(|level| level.peak)
+        .filter(|&peak| peak > noise_peak * 2.0)
+        .collect();
+    if speech_blocks.len() < speech.len() / 10 {
+        return Err("Could not hear any speech above the background noise. \
+                    Check that the right input device is selected and try again."
+            .into());
+    }
+    let speech_peak = percentile(speech_blocks.iter().copied(), 0.5);
+
+    // Halfway between background and speech on a log scale
+    let threshold = (noise_peak * speech_peak).sqrt().max(noise_peak * 1.5);
+    let threshold = (threshold * 1000.0).round() / 1000.0;
+    let hold_ms = recommend_hold_ms(&speech, threshold);
+
+    println!();
+    println!(
+        "Background noise peaks at {:.1} dBFS, speech at {:.1} dBFS.",
+        to_db(noise_peak),
+        to_db(speech_peak)
+    );
+    if speech_peak < noise_peak * 4.0 {
+        println!(
+            "Warning: your speech is only slightly louder than the background. \
+             Moving closer to the microphone or enabling `noise_reduction` may help."
+        );
+    }
+    println!("Recommended settings:");
+    println!("  noise_gate_threshold = {}", threshold);
+    println!("  noise_gate_hold_ms = {}", hold_ms);
+    if let VadKind::Energy = config.vad {
+        println!(
+            "Note: `vad = \"energy\"` does not use these settings, they only apply to `vad = \"peak\"`."
+        );
+    }
+
+    print!("Write these values to {}? [y/N] ", config_path);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        write_gate_settings(config_path, threshold, hold_ms)?;
+        println!("Saved to {}.", config_path);
+    }
+
+    Ok(())
+}
+
+/// Feeds captured samples into a ring buffer, dropping them while nobody is
+/// reading, e.g. while waiting for the user to press Enter.
+struct CaptureSink {
+    producer: Producer<f32>,
+}
+
+impl SampleSink for CaptureSink {
+    fn push_samples(&mut self, samples: &[f32]) {
+        let writable = samples.len().min(self.producer.slots());
+        if let Ok(chunk) = self.producer.write_chunk_uninit(writable) {
+            chunk.fill_from_iter(samples[..writable].iter().copied());
+        }
+    }
+}
+
+fn wait_for_enter() -> io::Result<()> {
+    print!("Press Enter to start...");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut String::new())?;
+    Ok(())
+}
+
+/// Records for `seconds`, drawing a live level meter, and returns the level
+/// of every block.
+fn record_levels(
+    consumer: &mut Consumer<f32>,
+    preprocessor: &mut Preprocessor,
+    block_len: usize,
+    seconds: f32,
+    learn_noise: bool,
+) -> Result<Vec<BlockLevel>, Box<dyn Error>> {
+    // Throw away whatever was captured while waiting
+    if let Ok(chunk) = consumer.read_chunk(consumer.slots()) {
+        chunk.commit_all();
+    }
+
+    let block_count = (seconds * 1000.0 / BLOCK_MS as f32) as usize;
+    let mut levels = Vec::with_capacity(block_count);
+    let mut pending = Vec::new();
+
+    while levels.len() < block_count {
+        if consumer.is_abandoned() && consumer.is_empty() {
+            return Err("The audio input stopped during calibration".into());
+        }
+
+        let available = consumer.slots();
+        if available == 0 {
+            std::thread::sleep(Duration::from_millis(5));
+            continue;
+        }
+        if let Ok(chunk) = consumer.read_chunk(available) {
+            let (first, second) = chunk.as_slices();
+            let samples: Vec<f32> = first.iter().chain(second).copied().collect();
+            chunk.commit_all();
+            pending.extend(preprocessor.process(&samples, learn_noise));
+        }
+
+        let mut blocks = pending.chunks_exact(block_len);
+        for block in &mut blocks {
+            levels.push(BlockLevel {
+                peak: block.iter().map(|s| s.abs()).fold(0.0, f32::max),
+                rms: (block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32).sqrt(),
+            });
+            if levels.len() % METER_INTERVAL_BLOCKS == 0 {
+                draw_meter(&levels[levels.len() - METER_INTERVAL_BLOCKS..])?;
+            }
+        }
+        let consumed = pending.len() - blocks.remainder().len();
+        pending.drain(..consumed);
+    }
+
+    println!();
+    levels.truncate(block_count);
+    Ok(levels)
+}
+
+fn draw_meter(levels: &[BlockLevel]) -> io::Result<()> {
+    let peak = levels.iter().map(|level| level.peak).fold(0.0, f32::max);
+    let rms = (levels
+        .iter()
+        .map(|level| level.rms * level.rms)
+        .sum::<f32>()
+        / levels.len() as f32)
+        .sqrt();
+
+    print!(
+        "\rPeak {} {:>6.1} dBFS   RMS {} {:>6.1} dBFS",
+        meter_bar(peak),
+        to_db(peak),
+        meter_bar(rms),
+        to_db(rms)
+    );
+    io::stdout().flush()
+}
+
+fn meter_bar(level: f32) -> String {
+    let fraction = (1.0 - to_db(level) / METER_FLOOR_DB).clamp(0.0, 1.0);
+    let filled = (fraction * METER_WIDTH as f32).round() as usize;
+    format!(
+        "[{}{}]",
+        "#".repeat(filled),
+        "-".repeat(METER_WIDTH - filled)
+    )
+}
+
+fn to_db(level: f32) -> f32 {
+    20.0 * level.max(1e-6).log10()
+}
+
+fn percentile(values: impl Iterator<Item = f32>, fraction: f32) -> f32 {
+    let mut values: Vec<f32> = values.collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f32::total_cmp);
+    let index = ((values.len() - 1) as f32 * fraction).round() as usize;
+    values[index]
+}
+
+/// Picks a hold time that bridges most of the short dips between words while
+/// speaking, so the gate does not flutter mid-sentence.
+fn recommend_hold_ms(speech: &[BlockLevel], threshold: f32) -> u64 {
+    let max_gap_blocks = (MAX_WORD_GAP_MS / BLOCK_MS) as usize;
+    let mut gaps = Vec::new();
+    let mut current_gap = 0usize;
+    let mut heard_speech = false;
+
+    for level in speech {
+        if level.peak > threshold {
+            if heard_speech && current_gap > 0 && current_gap <= max_gap_blocks {
+                gaps.push(current_gap as f32);
+            }
+            heard_speech = true;
+            current_gap = 0;
+        } else {
+            current_gap += 1;
+        }
+    }
+
+    let gap_ms = percentile(gaps.into_iter(), 0.9) * BLOCK_MS as f32;
+    // Round up to 50 ms steps within a sensible range
+    ((gap_ms / 50.0).ceil() as u64 * 50).clamp(100, MAX_WORD_GAP_MS as u64)
+}
+
+/// Updates the gate settings in the `[audio]` table of the config file,
+/// keeping the rest of the file and its comments as they are.
+fn write_gate_settings(
+    config_path: &str,
+    threshold: f32,
+    hold_ms: u64,
+) -> Result<(), Box<dyn Error>> {
+    let mut document: DocumentMut = fs::read_to_string(config_path)?.parse()?;
+    let audio = document
+        .get_mut("audio")
+        .and_then(Item::as_table_like_mut)
+        .ok_or("The config file has no [audio] section")?;
+
+    // Round in f64 so the file shows 0.023 rather than 0.02300000004
+    let threshold = (threshold as f64 * 1000.0).round() / 1000.0;
+    set_value(audio, "noise_gate_threshold", Value::from(threshold));
+    set_value(audio, "noise_gate_hold_ms", Value::from(hold_ms as i64));
+    // The deprecated setting would be ignored anyway, so drop it to avoid confusion
+    audio.remove("noise_gate_hold_time");
+
+    fs::write(config_path, document.to_string())?;
+    Ok(())
+}
+
+/// Replaces a value but keeps its surrounding whitespace and trailing comment.
+fn set_value(table: &mut dyn toml_edit::TableLike, key: &str, mut value: Value) {
+    match table.get_mut(key).and_then(Item::as_value_mut) {
+        Some(existing) => {
+            *value.decor_mut() = existing.decor().clone();
+            *existing = value;
+        }
+        None => {
+            table.insert(key, Item::Value(value));
+        }
+    }
+}
@@ -40,3 +40,33 @@ pub async fn send_to_chatbox(
 
     Ok(())
 }
+
+/// Shows text that is still changing, such as a live partial transcription.
+///
+/// It is sent with `immediate` off so VRChat treats it as a draft in the
+/// chatbox input rather than a finished message, without the notification
+/// sound. Only the end fits, which is the part that was just said.
+pub async fn send_partial_to_chatbox(
+    message: &str,
+    config: &Config,
+    socket: &UdpSocket,
+) -> Result<(), Box<dyn Error>> {
+    let osc_address = format!("{}:{}", config.osc.address, config.osc.output_port);
+
+    let chars: Vec<char> = message.chars().collect();
+    let tail: String = chars[chars.len().saturating_sub(144)..].iter().collect();
+
+    let osc_message = OscMessage {
+        addr: "/chatbox/input".to_string(),
+        args: vec![
+            OscType::String(tail),
+            OscType::Bool(false),
+            OscType::Bool(false),
+        ],
+    };
+
+    let buf = encode(&OscPacket::Message(osc_message))?;
+    socket.send_to(&buf, osc_address.as_str()).await?;
+
+    Ok(())
+}
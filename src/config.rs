@@ -1,13 +1,83 @@
+use crate::audio_encoding::UploadFormat;
+use crate::event_queue::OverflowPolicy;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+/// Shortest `partial_interval_ms` accepted. Snapshots more often than this
+/// would spend the rate limit before anything new has been said.
+pub const MIN_PARTIAL_INTERVAL_MS: u64 = 500;
+/// Shortest `max_utterance_seconds` accepted. The split point is searched
+/// for in the last second, so shorter limits would cut words apart.
+pub const MIN_MAX_UTTERANCE_SECONDS: f32 = 1.0;
 
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub osc: OscConfig,
     pub openai: OpenAiConfig,
     pub translation: TranslationConfig,
+    #[serde(default)]
+    pub transcription: TranscriptionConfig,
     pub audio: AudioConfig,
     pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub queue: QueueConfig,
     pub debug: bool,
+    pub listen: Option<ListenConfig>,
+}
+
+impl Config {
+    /// Rejects settings that parse but cannot work.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(ms) = self.transcription.partial_interval_ms {
+            if ms < MIN_PARTIAL_INTERVAL_MS {
+                return Err(format!(
+                    "partial_interval_ms = {} is too short, use at least {}",
+                    ms, MIN_PARTIAL_INTERVAL_MS
+                )
+                .into());
+            }
+        }
+        if let Some(seconds) = self.audio.max_utterance_seconds {
+            if seconds < MIN_MAX_UTTERANCE_SECONDS {
+                return Err(format!(
+                    "max_utterance_seconds = {} is too short, use at least {}",
+                    seconds, MIN_MAX_UTTERANCE_SECONDS
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Server settings for the OpenAI transcription engine.
+    pub fn transcription_api(&self) -> ApiSettings {
+        self.api_settings(&self.transcription.provider, "whisper-1")
+    }
+
+    /// Server settings for the chat model that translates.
+    pub fn translation_api(&self) -> ApiSettings {
+        self.api_settings(&self.translation.provider, &self.openai.model)
+    }
+
+    fn api_settings(&self, provider: &ProviderConfig, default_model: &str) -> ApiSettings {
+        ApiSettings {
+            base_url: provider
+                .base_url
+                .clone()
+                .unwrap_or_else(|| self.openai.base_url.clone()),
+            api_key: provider
+                .api_key
+                .clone()
+                .unwrap_or_else(|| self.openai.api_key.clone()),
+            model: provider
+                .model
+                .clone()
+                .unwrap_or_else(|| default_model.to_string()),
+            headers: provider.headers.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -23,23 +93,508 @@ pub struct OscConfig {
 pub struct OpenAiConfig {
     pub api_key: String,
     pub model: String,
+    /// Server for both transcription and translation, unless overridden in
+    /// their own sections.
+    #[serde(default = "default_openai_base_url")]
+    pub base_url: String,
+}
+
+/// Settings for talking to one OpenAI-compatible service, such as a
+/// self-hosted Whisper or chat server. Anything unset falls back to `[openai]`.
+#[derive(Deserialize, Clone, Default)]
+pub struct ProviderConfig {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    /// Extra HTTP headers sent with every request.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Where and how to send requests for one service, resolved from `[openai]`
+/// and the service's own section.
+#[derive(Clone)]
+pub struct ApiSettings {
+    pub base_url: String,
+    /// Empty for servers that do not need a key.
+    pub api_key: String,
+    pub model: String,
+    pub headers: HashMap<String, String>,
+}
+
+impl ApiSettings {
+    /// Full URL of an API path such as `chat/completions`.
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    /// Adds the API key, if any, and the extra headers to a request.
+    pub fn apply_to(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Whether requests go to OpenAI itself rather than a self-hosted server.
+    pub fn is_openai(&self) -> bool {
+        self.base_url
+            .trim_end_matches('/')
+            .eq_ignore_ascii_case(&default_openai_base_url())
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TranslationConfig {
     pub target_language: String,
     pub include_original_message: bool,
+    #[serde(flatten)]
+    pub provider: ProviderConfig,
+}
+
+/// Speech-to-text engine that turns recordings into text.
+#[derive(Deserialize, Clone)]
+pub struct TranscriptionConfig {
+    #[serde(default)]
+    pub engine: TranscriptionEngine,
+    /// Whisper GGML/GGUF model file for the local engine.
+    pub model_path: Option<PathBuf>,
+    /// CPU threads for the local engine. Uses every core when unset.
+    pub threads: Option<usize>,
+    /// ISO-639-1 code of the language you speak. Detected per utterance when unset.
+    pub language: Option<String>,
+    pub temperature: Option<f32>,
+    /// Example text in the style and spelling you want, sent as the prompt.
+    pub prompt: Option<String>,
+    /// Names and slang Whisper should spell correctly.
+    #[serde(default)]
+    pub glossary: Vec<String>,
+    /// How many recent transcriptions to add to the prompt as context.
+    #[serde(default = "default_prompt_history")]
+    pub prompt_history: usize,
+    /// Segments Whisper thinks are more likely than this to be silence are dropped.
+    #[serde(default = "default_no_speech_threshold")]
+    pub no_speech_threshold: f32,
+    /// Segments with a lower average token log probability are dropped.
+    #[serde(default = "default_logprob_threshold")]
+    pub logprob_threshold: f32,
+    /// Phrases Whisper makes up for noise. Segments containing one are dropped.
+    #[serde(default = "default_hallucination_blocklist")]
+    pub hallucination_blocklist: Vec<String>,
+    /// Transcribe what has been said so far this often while still speaking,
+    /// and show it in the chatbox until the translation is ready.
+    pub partial_interval_ms: Option<u64>,
+    /// What to ask the `openai` engine's server for. When unset,
+    /// `verbose_json` is tried first and `json` is used if it is rejected.
+    pub response_format: Option<ResponseFormat>,
+    #[serde(flatten)]
+    pub provider: ProviderConfig,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        TranscriptionConfig {
+            engine: TranscriptionEngine::default(),
+            model_path: None,
+            threads: None,
+            language: None,
+            temperature: None,
+            prompt: None,
+            glossary: Vec::new(),
+            prompt_history: default_prompt_history(),
+            no_speech_threshold: default_no_speech_threshold(),
+            logprob_threshold: default_logprob_threshold(),
+            hallucination_blocklist: default_hallucination_blocklist(),
+            partial_interval_ms: None,
+            response_format: None,
+            provider: ProviderConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptionEngine {
+    /// OpenAI's hosted Whisper API.
+    #[default]
+    OpenAi,
+    /// A Whisper model running on this PC. Needs the `whisper` feature.
+    Local,
+}
+
+/// Response format of an OpenAI-style transcription endpoint.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Text with per-segment confidence for the hallucination filter.
+    VerboseJson,
+    /// Text only, for models and servers without `verbose_json` such as
+    /// gpt-4o-transcribe. Only the blocklist can filter it.
+    Json,
+}
+
+impl ResponseFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResponseFormat::VerboseJson => "verbose_json",
+            ResponseFormat::Json => "json",
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct AudioConfig {
-    pub silence_threshold: u32,
+    pub silence_ms: Option<u64>,
+    /// Deprecated: counts audio callbacks, so its duration depends on the device.
+    pub silence_threshold: Option<u32>,
     pub noise_gate_threshold: f32,
-    pub noise_gate_hold_time: f32,
+    pub noise_gate_hold_ms: Option<u64>,
+    /// Deprecated: hold time in seconds, superseded by `noise_gate_hold_ms`.
+    pub noise_gate_hold_time: Option<f32>,
     pub min_transcription_duration: f32,
+    #[serde(default)]
+    pub vad: VadKind,
+    #[serde(default = "default_energy_open_db")]
+    pub energy_open_db: f32,
+    #[serde(default = "default_energy_close_db")]
+    pub energy_close_db: f32,
+    pub device: Option<String>,
+    pub host: Option<String>,
+    /// Input channels to record, numbered from 1. All channels are mixed when unset.
+    pub channels: Option<Vec<u16>>,
+    #[serde(default = "default_high_pass")]
+    pub high_pass: bool,
+    #[serde(default = "default_high_pass_hz")]
+    pub high_pass_hz: f32,
+    #[serde(default)]
+    pub noise_reduction: bool,
+    #[serde(default = "default_noise_reduction_db")]
+    pub noise_reduction_db: f32,
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+    #[serde(default = "default_normalize_target_db")]
+    pub normalize_target_db: f32,
+    #[serde(default = "default_normalize_peak_db")]
+    pub normalize_peak_db: f32,
+    #[serde(default = "default_target_sample_rate")]
+    pub target_sample_rate: u32,
+    #[serde(default)]
+    pub upload_format: UploadFormat,
+    #[serde(default = "default_opus_bitrate")]
+    pub opus_bitrate: i32,
+    #[serde(default = "default_pre_roll_ms")]
+    pub pre_roll_ms: u64,
+    #[serde(default = "default_trim_silence")]
+    pub trim_silence: bool,
+    #[serde(default = "default_trim_margin_ms")]
+    pub trim_margin_ms: u64,
+    pub max_utterance_seconds: Option<f32>,
+    pub push_to_talk_parameter: Option<String>,
+    pub level_warning_parameter: Option<String>,
+    #[serde(default = "default_respect_mute")]
+    pub respect_mute: bool,
+}
+
+/// Limits on utterances waiting to be transcribed while the main loop is busy.
+#[derive(Deserialize, Clone)]
+pub struct QueueConfig {
+    #[serde(default = "default_max_queued_utterances")]
+    pub max_utterances: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_utterances: default_max_queued_utterances(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// Second pipeline that translates what other players say, captured from a
+/// loopback or monitor input, and shows it locally instead of in the chatbox.
+#[derive(Deserialize, Clone)]
+pub struct ListenConfig {
+    /// Input device that carries the game audio, e.g. a PulseAudio monitor source.
+    pub device: String,
+    pub host: Option<String>,
+    /// Language to translate other players into, usually your own.
+    pub target_language: String,
+    /// Also append every translation to this file.
+    pub output_file: Option<PathBuf>,
+    /// Game audio is usually much louder than a microphone.
+    pub noise_gate_threshold: Option<f32>,
+    /// Language other players speak, if you know it. Detected per utterance when unset.
+    pub language: Option<String>,
+}
+
+impl ListenConfig {
+    /// Builds the config for the listening pipeline: the same audio settings
+    /// as the microphone but on the listen device, translating into
+    /// `target_language`, and ignoring push-to-talk and mute.
+    pub fn pipeline_config(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.listen = None;
+        config.translation.target_language = self.target_language.clone();
+        config.translation.include_original_message = true;
+        config.transcription.language = self.language.clone();
+        config.transcription.partial_interval_ms = None;
+
+        let audio = &mut config.audio;
+        audio.device = Some(self.device.clone());
+        audio.host = self.host.clone().or(audio.host.take());
+        audio.channels = None;
+        audio.push_to_talk_parameter = None;
+        audio.level_warning_parameter = None;
+        audio.respect_mute = false;
+        if let Some(threshold) = self.noise_gate_threshold {
+            audio.noise_gate_threshold = threshold;
+        }
+
+        config
+    }
+}
+
+/// Voice activity detector used to start and stop recordings.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VadKind {
+    /// Fixed peak-amplitude noise gate.
+    #[default]
+    Peak,
+    /// Frame RMS energy against an adaptive noise floor.
+    Energy,
+}
+
+/// How long the input has to stay quiet before an utterance ends.
+#[derive(Clone, Copy)]
+pub enum SilenceDuration {
+    Millis(u64),
+    /// Legacy `silence_threshold` value, resolved once the callback size is known.
+    CallbackFrames(u32),
+}
+
+impl AudioConfig {
+    pub fn silence_duration(&self) -> SilenceDuration {
+        match (self.silence_ms, self.silence_threshold) {
+            (Some(ms), _) => SilenceDuration::Millis(ms),
+            (None, Some(frames)) => SilenceDuration::CallbackFrames(frames),
+            (None, None) => SilenceDuration::Millis(1000),
+        }
+    }
+
+    pub fn noise_gate_hold_duration_ms(&self) -> u64 {
+        match (self.noise_gate_hold_ms, self.noise_gate_hold_time) {
+            (Some(ms), _) => ms,
+            (None, Some(seconds)) => (seconds * 1000.0) as u64,
+            (None, None) => 200,
+        }
+    }
+}
+
+fn default_openai_base_url() -> String {
+    "https://api.openai.com/v1".to_string()
+}
+
+fn default_prompt_history() -> usize {
+    2
+}
+
+fn default_no_speech_threshold() -> f32 {
+    0.6
+}
+
+fn default_logprob_threshold() -> f32 {
+    -1.0
+}
+
+fn default_hallucination_blocklist() -> Vec<String> {
+    [
+        "Thanks for watching",
+        "Thank you for watching",
+        "Please subscribe",
+        "Like and subscribe",
+        "Subtitles by",
+        "Amara.org",
+        "ご視聴ありがとうございました",
+        "チャンネル登録",
+    ]
+    .iter()
+    .map(|phrase| phrase.to_string())
+    .collect()
+}
+
+fn default_target_sample_rate() -> u32 {
+    16000
+}
+
+fn default_opus_bitrate() -> i32 {
+    24000
+}
+
+fn default_pre_roll_ms() -> u64 {
+    300
+}
+
+fn default_trim_silence() -> bool {
+    true
+}
+
+fn default_trim_margin_ms() -> u64 {
+    200
+}
+
+fn default_respect_mute() -> bool {
+    true
+}
+
+fn default_energy_open_db() -> f32 {
+    9.0
+}
+
+fn default_energy_close_db() -> f32 {
+    4.0
 }
 
 #[derive(Deserialize, Clone)]
 pub struct RateLimitConfig {
     pub requests_per_minute: usize,
 }
+
+fn default_high_pass() -> bool {
+    true
+}
+
+fn default_high_pass_hz() -> f32 {
+    80.0
+}
+
+fn default_noise_reduction_db() -> f32 {
+    12.0
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+fn default_normalize_target_db() -> f32 {
+    -20.0
+}
+
+fn default_normalize_peak_db() -> f32 {
+    -1.0
+}
+
+fn default_max_queued_utterances() -> usize {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::test_config;
+
+    #[test]
+    fn falls_back_to_openai_settings() {
+        let config = test_config("");
+        let transcription = config.transcription_api();
+        assert_eq!(transcription.base_url, "https://api.openai.com/v1");
+        assert_eq!(transcription.api_key, "test");
+        assert_eq!(transcription.model, "whisper-1");
+        assert!(transcription.headers.is_empty());
+
+        let translation = config.translation_api();
+        assert_eq!(translation.base_url, "https://api.openai.com/v1");
+        assert_eq!(translation.model, "gpt-4o-mini");
+    }
+
+    #[test]
+    fn prefers_each_section_over_openai() {
+        let mut config = test_config(
+            r#"
+[transcription]
+base_url = "http://localhost:8000/v1"
+model = "Systran/faster-whisper-small"
+api_key = ""
+headers = { "X-Example" = "value" }
+"#,
+        );
+        config.openai.base_url = "http://proxy.local/v1".to_string();
+
+        let transcription = config.transcription_api();
+        assert_eq!(transcription.base_url, "http://localhost:8000/v1");
+        assert_eq!(transcription.model, "Systran/faster-whisper-small");
+        assert_eq!(transcription.api_key, "");
+        assert_eq!(transcription.headers["X-Example"], "value");
+
+        // Translation has no settings of its own, so it follows [openai]
+        let translation = config.translation_api();
+        assert_eq!(translation.base_url, "http://proxy.local/v1");
+        assert_eq!(translation.api_key, "test");
+        assert!(translation.headers.is_empty());
+    }
+
+    #[test]
+    fn joins_endpoints_with_or_without_a_trailing_slash() {
+        let mut api = test_config("").translation_api();
+        assert_eq!(
+            api.endpoint("chat/completions"),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        api.base_url = "http://localhost:1234/v1/".to_string();
+        assert_eq!(
+            api.endpoint("chat/completions"),
+            "http://localhost:1234/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn recognizes_openai_itself() {
+        let mut api = test_config("").translation_api();
+        assert!(api.is_openai());
+
+        api.base_url = "https://API.openai.com/v1/".to_string();
+        assert!(api.is_openai());
+
+        for base_url in [
+            "http://localhost:8000/v1",
+            "https://api.openai.com/v1/proxy",
+            "https://api.openai.com",
+        ] {
+            api.base_url = base_url.to_string();
+            assert!(!api.is_openai(), "{}", base_url);
+        }
+    }
+
+    #[test]
+    fn rejects_utterance_limits_below_a_second() {
+        assert!(test_config("max_utterance_seconds = 1.0")
+            .validate()
+            .is_ok());
+        assert!(test_config("max_utterance_seconds = 0.5")
+            .validate()
+            .is_err());
+        assert!(test_config("max_utterance_seconds = 0.0")
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_partial_intervals_below_the_minimum() {
+        assert!(test_config("").validate().is_ok());
+        assert!(test_config("[transcription]\npartial_interval_ms = 500")
+            .validate()
+            .is_ok());
+        assert!(test_config("[transcription]\npartial_interval_ms = 499")
+            .validate()
+            .is_err());
+        assert!(test_config("[transcription]\npartial_interval_ms = 0")
+            .validate()
+            .is_err());
+    }
+}
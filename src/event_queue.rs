@@ -0,0 +1,372 @@
+use crate::audio_encoding::{AudioEncoder, EncodedAudio};
+use crate::types::AudioEvent;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What to do with a new utterance when `max_utterances` are already waiting.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the utterance that has been waiting longest.
+    DropOldest,
+    /// Drop the new utterance.
+    DropNewest,
+    /// Append the new utterance to the last waiting one, so nothing is lost
+    /// but it is translated as one longer message.
+    #[default]
+    Merge,
+}
+
+/// Counters for utterances the queue could not deliver separately.
+#[derive(Default)]
+pub struct QueueStats {
+    pub dropped: AtomicUsize,
+    pub merged: AtomicUsize,
+}
+
+struct Shared {
+    events: Mutex<VecDeque<AudioEvent>>,
+    notify: Notify,
+    max_utterances: usize,
+    policy: OverflowPolicy,
+    encoder: AudioEncoder,
+    stats: QueueStats,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+}
+
+/// Creates the queue that carries events from a recorder thread to the main
+/// loop. At most `max_utterances` utterances wait at once; control events
+/// such as `StartRecording` are small and always queued, and only the newest
+/// partial snapshot is kept.
+pub fn event_queue(
+    max_utterances: usize,
+    policy: OverflowPolicy,
+    encoder: AudioEncoder,
+) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        events: Mutex::new(VecDeque::new()),
+        notify: Notify::new(),
+        max_utterances: max_utterances.max(1),
+        policy,
+        encoder,
+        stats: QueueStats::default(),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+    });
+
+    (
+        EventSender {
+            shared: Arc::clone(&shared),
+        },
+        EventReceiver { shared },
+    )
+}
+
+/// Sending half, used from the recorder threads. Never blocks.
+pub struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: AudioEvent) {
+        let shared = &self.shared;
+        let mut events = shared.events.lock().unwrap_or_else(|e| e.into_inner());
+
+        let audio = match event {
+            AudioEvent::AudioData(audio) => {
+                // The final audio makes any snapshot still waiting pointless
+                events.retain(|event| !matches!(event, AudioEvent::PartialAudio(_)));
+                audio
+            }
+            AudioEvent::PartialAudio(audio) => {
+                // Only the newest snapshot is worth transcribing
+                match events
+                    .iter_mut()
+                    .find(|event| matches!(event, AudioEvent::PartialAudio(_)))
+                {
+                    Some(queued) => *queued = AudioEvent::PartialAudio(audio),
+                    None => events.push_back(AudioEvent::PartialAudio(audio)),
+                }
+                drop(events);
+                shared.notify.notify_one();
+                return;
+            }
+            event => {
+                events.push_back(event);
+                drop(events);
+                shared.notify.notify_one();
+                return;
+            }
+        };
+
+        let waiting = events
+            .iter()
+            .filter(|event| matches!(event, AudioEvent::AudioData(_)))
+            .count();
+        if waiting < shared.max_utterances {
+            events.push_back(AudioEvent::AudioData(audio));
+            drop(events);
+            shared.notify.notify_one();
+            return;
+        }
+
+        match shared.policy {
+            OverflowPolicy::DropOldest => {
+                if let Some(oldest) = events
+                    .iter()
+                    .position(|event| matches!(event, AudioEvent::AudioData(_)))
+                {
+                    events.remove(oldest);
+                }
+                events.push_back(AudioEvent::AudioData(audio));
+                let dropped = shared.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!(
+                    "Warning: too many utterances waiting, dropped the oldest one ({} dropped so far)",
+                    dropped
+                );
+            }
+            OverflowPolicy::DropNewest => {
+                let dropped = shared.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!(
+                    "Warning: too many utterances waiting, dropped the newest one ({} dropped so far)",
+                    dropped
+                );
+            }
+            OverflowPolicy::Merge => {
+                let last = events.iter_mut().rev().find_map(|event| match event {
+                    AudioEvent::AudioData(queued) => Some(queued),
+                    _ => None,
+                });
+                let result = last.map(|queued| {
+                    merge_audio(&shared.encoder, queued, &audio).map(|merged| *queued = merged)
+                });
+                match result {
+                    Some(Ok(())) => {
+                        let merged = shared.stats.merged.fetch_add(1, Ordering::Relaxed) + 1;
+                        eprintln!(
+                            "Warning: too many utterances waiting, merged the newest one into the previous one ({} merged so far)",
+                            merged
+                        );
+                    }
+                    Some(Err(e)) => {
+                        let dropped = shared.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                        eprintln!(
+                            "Error merging queued audio, dropped the newest utterance ({} dropped so far): {}",
+                            dropped, e
+                        );
+                    }
+                    // Nothing to merge into, which `waiting` rules out, so keep it
+                    None => events.push_back(AudioEvent::AudioData(audio)),
+                }
+            }
+        }
+
+        drop(events);
+        shared.notify.notify_one();
+    }
+
+    /// Whether the receiving side has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_closed.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.shared.stats
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        EventSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+/// Receiving half, used by the main loop.
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+impl EventReceiver {
+    /// Waits for the next event. Returns `None` once every sender has been
+    /// dropped and the queue is empty.
+    pub async fn recv(&mut self) -> Option<AudioEvent> {
+        loop {
+            let event = self
+                .shared
+                .events
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front();
+            if event.is_some() {
+                return event;
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            // A notification sent since the check above is stored as a permit
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.shared.stats
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Relaxed);
+    }
+}
+
+fn merge_audio(
+    encoder: &AudioEncoder,
+    first: &EncodedAudio,
+    second: &EncodedAudio,
+) -> Result<EncodedAudio, Box<dyn std::error::Error>> {
+    let samples = [first.samples.as_slice(), second.samples.as_slice()].concat();
+    let mut merged = encoder.encode(&samples, first.sample_rate)?;
+    merged.levels = match (first.levels, second.levels) {
+        (Some(a), Some(b)) => {
+            Some(a.combine(&b, first.samples.len() as f32, second.samples.len() as f32))
+        }
+        (a, b) => a.or(b),
+    };
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+    use std::time::Duration;
+
+    fn queue(max_utterances: usize, policy: OverflowPolicy) -> (EventSender, EventReceiver) {
+        let config = test_config("");
+        event_queue(max_utterances, policy, AudioEncoder::new(&config.audio))
+    }
+
+    /// `millis` of audio at 16 kHz.
+    fn audio(millis: usize) -> EncodedAudio {
+        let config = test_config("");
+        AudioEncoder::new(&config.audio)
+            .encode(&vec![0.1; millis * 16], 16000)
+            .unwrap()
+    }
+
+    /// Everything queued, with audio events reduced to their length in ms.
+    async fn drain(rx: &mut EventReceiver) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::ZERO, rx.recv()).await {
+            events.push(match event {
+                AudioEvent::AudioData(audio) => format!("audio {}", audio.duration.as_millis()),
+                AudioEvent::PartialAudio(audio) => {
+                    format!("partial {}", audio.duration.as_millis())
+                }
+                AudioEvent::StartRecording => "start".to_string(),
+                AudioEvent::StopRecording => "stop".to_string(),
+                _ => "device".to_string(),
+            });
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn keeps_only_the_newest_partial_in_its_place() {
+        let (tx, mut rx) = queue(3, OverflowPolicy::Merge);
+        tx.send(AudioEvent::StartRecording);
+        tx.send(AudioEvent::PartialAudio(audio(100)));
+        tx.send(AudioEvent::StopRecording);
+        tx.send(AudioEvent::PartialAudio(audio(200)));
+
+        assert_eq!(drain(&mut rx).await, ["start", "partial 200", "stop"]);
+    }
+
+    #[tokio::test]
+    async fn discards_waiting_partials_when_the_utterance_arrives() {
+        let (tx, mut rx) = queue(3, OverflowPolicy::Merge);
+        tx.send(AudioEvent::StartRecording);
+        tx.send(AudioEvent::PartialAudio(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(300)));
+        tx.send(AudioEvent::StopRecording);
+
+        assert_eq!(drain(&mut rx).await, ["start", "audio 300", "stop"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest_utterances() {
+        let (tx, mut rx) = queue(2, OverflowPolicy::DropOldest);
+        tx.send(AudioEvent::AudioData(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(200)));
+        tx.send(AudioEvent::AudioData(audio(300)));
+
+        assert_eq!(drain(&mut rx).await, ["audio 200", "audio 300"]);
+        assert_eq!(rx.stats().dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.stats().merged.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_waiting_utterances() {
+        let (tx, mut rx) = queue(2, OverflowPolicy::DropNewest);
+        tx.send(AudioEvent::AudioData(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(200)));
+        tx.send(AudioEvent::AudioData(audio(300)));
+
+        assert_eq!(drain(&mut rx).await, ["audio 100", "audio 200"]);
+        assert_eq!(rx.stats().dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.stats().merged.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn merge_appends_to_the_last_waiting_utterance() {
+        let (tx, mut rx) = queue(2, OverflowPolicy::Merge);
+        tx.send(AudioEvent::AudioData(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(200)));
+        tx.send(AudioEvent::StopRecording);
+        tx.send(AudioEvent::AudioData(audio(300)));
+
+        assert_eq!(drain(&mut rx).await, ["audio 100", "audio 500", "stop"]);
+        assert_eq!(rx.stats().merged.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.stats().dropped.load(Ordering::Relaxed), 0);
+    }
+
+    // Opus encoding, and so merging, only fails without the `opus` feature
+    #[cfg(not(feature = "opus"))]
+    #[tokio::test]
+    async fn merge_counts_an_utterance_it_could_not_merge_as_dropped() {
+        let config = test_config("upload_format = \"opus\"");
+        let (tx, mut rx) = event_queue(1, OverflowPolicy::Merge, AudioEncoder::new(&config.audio));
+        tx.send(AudioEvent::AudioData(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(200)));
+
+        assert_eq!(drain(&mut rx).await, ["audio 100"]);
+        assert_eq!(rx.stats().dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.stats().merged.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn partials_do_not_count_towards_max_utterances() {
+        let (tx, mut rx) = queue(1, OverflowPolicy::DropNewest);
+        tx.send(AudioEvent::PartialAudio(audio(100)));
+        tx.send(AudioEvent::AudioData(audio(300)));
+        tx.send(AudioEvent::PartialAudio(audio(200)));
+
+        assert_eq!(drain(&mut rx).await, ["audio 300", "partial 200"]);
+        assert_eq!(rx.stats().dropped.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::audio_filters::speech_level;
+use crate::config::Config;
+use crate::osc_listener::parameter_address;
+use rosc::{encoder::encode, OscMessage, OscPacket, OscType};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Utterances in a row that have to be clipping or too quiet before warning,
+/// so a single shout or mumble does not trigger it.
+const CONSECUTIVE_UTTERANCES: usize = 3;
+/// Warn when more than this fraction of an utterance is at full scale.
+const CLIPPED_RATIO_LIMIT: f32 = 0.001;
+/// Warn when speech averages below this level in dBFS.
+const QUIET_LEVEL_DB: f32 = -40.0;
+
+/// Input levels of one utterance, measured before normalization.
+#[derive(Clone, Copy, Serialize)]
+pub struct InputLevels {
+    /// Fraction of samples that were clipped at the input.
+    pub clipped_ratio: f32,
+    /// Average speech level in dBFS, or `None` if nothing was above the
+    /// loudness gate.
+    pub speech_level_db: Option<f32>,
+}
+
+impl InputLevels {
+    pub fn measure(samples: &[f32], clipped_ratio: f32, sample_rate: u32) -> Self {
+        InputLevels {
+            clipped_ratio,
+            speech_level_db: speech_level(samples, sample_rate).map(|rms| 20.0 * rms.log10()),
+        }
+    }
+
+    /// Levels of two utterances joined together, weighted by their lengths.
+    pub fn combine(&self, other: &InputLevels, weight: f32, other_weight: f32) -> Self {
+        let total = (weight + other_weight).max(f32::MIN_POSITIVE);
+        let mean = |a: f32, b: f32| (a * weight + b * other_weight) / total;
+        InputLevels {
+            clipped_ratio: mean(self.clipped_ratio, other.clipped_ratio),
+            speech_level_db: match (self.speech_level_db, other.speech_level_db) {
+                (Some(a), Some(b)) => Some(mean(a, b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+/// Problem with the microphone level that has lasted several utterances.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LevelWarning {
+    Clipping,
+    TooQuiet,
+}
+
+/// Watches the levels of recorded utterances and tells the user, in the
+/// console and optionally through an avatar int parameter, when the input
+/// is consistently clipping or too quiet.
+///
+/// The parameter is 0 while the level is fine, 1 while clipping and 2 while
+/// too quiet.
+pub struct LevelMonitor {
+    socket: Arc<UdpSocket>,
+    config: Arc<Config>,
+    clipping_streak: usize,
+    quiet_streak: usize,
+    warning: Option<LevelWarning>,
+}
+
+impl LevelMonitor {
+    pub fn new(socket: Arc<UdpSocket>, config: Arc<Config>) -> Self {
+        LevelMonitor {
+            socket,
+            config,
+            clipping_streak: 0,
+            quiet_streak: 0,
+            warning: None,
+        }
+    }
+
+    pub async fn check(&mut self, levels: &InputLevels) {
+        if self.config.debug {
+            println!(
+                "Input levels: {:.2}% clipped, speech at {}",
+                levels.clipped_ratio * 100.0,
+                format_level(levels.speech_level_db)
+            );
+        }
+
+        if levels.clipped_ratio > CLIPPED_RATIO_LIMIT {
+            self.clipping_streak += 1;
+        } else {
+            self.clipping_streak = 0;
+        }
+        if levels
+            .speech_level_db
+            .map_or(true, |level| level < QUIET_LEVEL_DB)
+        {
+            self.quiet_streak += 1;
+        } else {
+            self.quiet_streak = 0;
+        }
+
+        let warning = if self.clipping_streak >= CONSECUTIVE_UTTERANCES {
+            Some(LevelWarning::Clipping)
+        } else if self.quiet_streak >= CONSECUTIVE_UTTERANCES {
+            Some(LevelWarning::TooQuiet)
+        } else if self.clipping_streak == 0 && self.quiet_streak == 0 {
+            None
+        } else {
+            // Keep the current state until it is clearly over
+            self.warning
+        };
+        if warning == self.warning {
+            return;
+        }
+        self.warning = warning;
+
+        match warning {
+            Some(LevelWarning::Clipping) => println!(
+                "Warning: your microphone is clipping ({:.1}% of the last recording was at full scale). \
+                 Turn down its input gain, distorted audio is transcribed badly.",
+                levels.clipped_ratio * 100.0
+            ),
+            Some(LevelWarning::TooQuiet) => println!(
+                "Warning: your microphone is very quiet (speech at {}). \
+                 Turn up its input gain or move closer to it.",
+                format_level(levels.speech_level_db)
+            ),
+            None => println!("Microphone level is back to normal."),
+        }
+        self.send_parameter().await;
+    }
+
+    async fn send_parameter(&self) {
+        let name = match &self.config.audio.level_warning_parameter {
+            Some(name) => name,
+            None => return,
+        };
+        let value = match self.warning {
+            None => 0,
+            Some(LevelWarning::Clipping) => 1,
+            Some(LevelWarning::TooQuiet) => 2,
+        };
+
+        let message = OscMessage {
+            addr: parameter_address(name),
+            args: vec![OscType::Int(value)],
+        };
+        if let Ok(buf) = encode(&OscPacket::Message(message)) {
+            let osc_address = format!(
+                "{}:{}",
+                self.config.osc.address, self.config.osc.output_port
+            );
+            if let Err(e) = self.socket.send_to(&buf, osc_address.as_str()).await {
+                eprintln!("Error sending level warning parameter: {}", e);
+            }
+        }
+    }
+}
+
+fn format_level(level_db: Option<f32>) -> String {
+    match level_db {
+        Some(level_db) => format!("{:.1} dBFS", level_db),
+        None => "below -50 dBFS".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    fn levels(clipped_ratio: f32, speech_level_db: Option<f32>) -> InputLevels {
+        InputLevels {
+            clipped_ratio,
+            speech_level_db,
+        }
+    }
+
+    #[test]
+    fn measures_the_speech_level_in_dbfs() {
+        // A constant 0.1 has an RMS of 0.1, or -20 dBFS
+        let measured = InputLevels::measure(&vec![0.1; 16000], 0.25, SAMPLE_RATE);
+        assert_eq!(measured.clipped_ratio, 0.25);
+        assert!((measured.speech_level_db.unwrap() + 20.0).abs() < 0.01);
+
+        // Pauses do not pull the level down
+        let mut with_pause = vec![0.1; 16000];
+        with_pause.extend(vec![0.0; 16000]);
+        let measured = InputLevels::measure(&with_pause, 0.0, SAMPLE_RATE);
+        assert!((measured.speech_level_db.unwrap() + 20.0).abs() < 0.01);
+
+        let silence = InputLevels::measure(&vec![0.0; 16000], 0.0, SAMPLE_RATE);
+        assert_eq!(silence.speech_level_db, None);
+    }
+
+    #[test]
+    fn combines_levels_weighted_by_length() {
+        let combined = levels(0.03, Some(-20.0)).combine(&levels(0.0, Some(-40.0)), 1.0, 3.0);
+        assert!((combined.clipped_ratio - 0.0075).abs() < 1e-6);
+        assert_eq!(combined.speech_level_db, Some(-35.0));
+
+        // An utterance without speech does not drag the level down
+        let combined = levels(0.0, None).combine(&levels(0.0, Some(-30.0)), 1.0, 1.0);
+        assert_eq!(combined.speech_level_db, Some(-30.0));
+        let combined = levels(0.0, None).combine(&levels(0.0, None), 0.0, 0.0);
+        assert_eq!(combined.speech_level_db, None);
+        assert_eq!(combined.clipped_ratio, 0.0);
+    }
+
+    async fn monitor() -> LevelMonitor {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        LevelMonitor::new(socket, Arc::new(test_config("")))
+    }
+
+    #[tokio::test]
+    async fn warns_about_clipping_after_three_utterances_in_a_row() {
+        let mut monitor = monitor().await;
+        let clipping = levels(0.002, Some(-10.0));
+        let fine = levels(0.001, Some(-20.0));
+
+        monitor.check(&clipping).await;
+        monitor.check(&clipping).await;
+        monitor.check(&fine).await;
+        monitor.check(&clipping).await;
+        monitor.check(&clipping).await;
+        assert!(monitor.warning.is_none());
+
+        monitor.check(&clipping).await;
+        assert!(monitor.warning == Some(LevelWarning::Clipping));
+
+        monitor.check(&fine).await;
+        assert!(monitor.warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn warns_about_quiet_input_including_utterances_without_speech() {
+        let mut monitor = monitor().await;
+        monitor.check(&levels(0.0, Some(-40.5))).await;
+        monitor.check(&levels(0.0, None)).await;
+        assert!(monitor.warning.is_none());
+        monitor.check(&levels(0.0, Some(-45.0))).await;
+        assert!(monitor.warning == Some(LevelWarning::TooQuiet));
+
+        // Exactly at the limit is loud enough
+        monitor.check(&levels(0.0, Some(-40.0))).await;
+        assert!(monitor.warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_warning_while_problems_alternate() {
+        let mut monitor = monitor().await;
+        let clipping = levels(0.01, Some(-10.0));
+        let quiet = levels(0.0, Some(-50.0));
+        for _ in 0..3 {
+            monitor.check(&clipping).await;
+        }
+        assert!(monitor.warning == Some(LevelWarning::Clipping));
+
+        // Neither streak is back to zero, so the warning stays until one is
+        monitor.check(&quiet).await;
+        assert!(monitor.warning == Some(LevelWarning::Clipping));
+        monitor.check(&quiet).await;
+        monitor.check(&quiet).await;
+        assert!(monitor.warning == Some(LevelWarning::TooQuiet));
+    }
+}
@@ -1,11 +1,26 @@
+pub mod audio_conditioning;
+pub mod audio_devices;
+pub mod audio_encoding;
+pub mod audio_filters;
 pub mod audio_processing;
 pub mod audio_recording;
+pub mod audio_source;
+pub mod calibration;
 pub mod chatbox;
 pub mod config;
+pub mod event_queue;
+pub mod flac;
+pub mod input_levels;
+#[cfg(feature = "opus")]
+pub mod ogg_opus;
+pub mod osc_listener;
 pub mod price_estimator;
 pub mod rate_limiter;
 pub mod recording_manager;
+#[cfg(test)]
+mod test_support;
 pub mod transcription;
 pub mod translation;
 pub mod types;
 pub mod typing_indicator;
+pub mod voice_activity;
@@ -1,19 +1,28 @@
-use babble_boop::audio_processing::process_audio;
-use babble_boop::audio_recording::start_audio_recording;
-use babble_boop::config::Config;
+use babble_boop::audio_devices::list_input_devices;
+use babble_boop::audio_encoding::{AudioEncoder, UploadFormat};
+use babble_boop::audio_processing::{
+    process_audio, process_heard_audio, process_partial_audio, Translator,
+};
+use babble_boop::audio_recording::{record_microphone, start_audio_recording};
+use babble_boop::audio_source::WavReplaySource;
+use babble_boop::calibration::run_calibration;
+use babble_boop::config::{Config, TranscriptionEngine};
+use babble_boop::event_queue::{event_queue, EventReceiver, EventSender};
+use babble_boop::input_levels::LevelMonitor;
+use babble_boop::osc_listener::ParameterListener;
 use babble_boop::price_estimator::PriceEstimator;
-use babble_boop::rate_limiter::RateLimiter;
 use babble_boop::recording_manager::RecordingManager;
-use babble_boop::types::AudioEvent;
+use babble_boop::transcription::create_transcriber;
+use babble_boop::types::{AudioEvent, RecorderControls};
 use babble_boop::typing_indicator::TypingIndicator;
 
 use std::error::Error;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -21,7 +30,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     std::panic::set_hook(Box::new(|panic_info| {
         eprintln!("Panic occurred: {}", panic_info);
 
-        println!("");
+        println!();
         println!("Press Enter to exit...");
         io::stdout().flush().unwrap();
         let _ = io::stdin().read_line(&mut String::new());
@@ -29,7 +38,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let result = run_main().await;
 
-    println!("");
+    println!();
     println!("Press Enter to exit...");
     io::stdout().flush().unwrap();
     let _ = io::stdin().read_line(&mut String::new());
@@ -37,7 +46,51 @@ async fn main() -> Result<(), Box<dyn Error>> {
     result
 }
 
+/// Command line options. The app normally runs without any.
+struct Args {
+    list_devices: bool,
+    calibrate: bool,
+    replay: Option<PathBuf>,
+    replay_speed: f32,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut args = Args {
+            list_devices: false,
+            calibrate: false,
+            replay: None,
+            replay_speed: 1.0,
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--list-devices" => args.list_devices = true,
+                "--calibrate" => args.calibrate = true,
+                "--replay" => {
+                    let path = iter
+                        .next()
+                        .ok_or("--replay needs a WAV file or directory")?;
+                    args.replay = Some(PathBuf::from(path));
+                }
+                "--replay-speed" => {
+                    let speed = iter.next().ok_or("--replay-speed needs a number")?;
+                    args.replay_speed = speed.parse()?;
+                }
+                other => return Err(format!("Unknown argument: {}", other).into()),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
 async fn run_main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse()?;
+    if args.list_devices {
+        return list_input_devices();
+    }
 
     let config_path = "config.toml";
     let config_data = match fs::read_to_string(config_path) {
@@ -51,6 +104,17 @@ async fn run_main() -> Result<(), Box<dyn Error>> {
     };
 
     let config: Config = toml::from_str(&config_data)?;
+    config.validate()?;
+    if config.audio.upload_format == UploadFormat::Opus && !cfg!(feature = "opus") {
+        return Err(
+            "upload_format = \"opus\" needs the `opus` feature, rebuild with `cargo build --features opus`"
+                .into(),
+        );
+    }
+    if args.calibrate {
+        return run_calibration(config_path, &config.audio);
+    }
+    let transcriber = create_transcriber(&config)?;
     let config = Arc::new(config);
 
     let socket_address = format!("{}:{}", config.osc.address, config.osc.input_port);
@@ -63,22 +127,73 @@ async fn run_main() -> Result<(), Box<dyn Error>> {
         config.rate_limit.requests_per_minute
     );
     println!("Debug mode: {}", config.debug);
+    match config.transcription.engine {
+        TranscriptionEngine::OpenAi => {
+            println!("Upload format: {}", config.audio.upload_format.extension())
+        }
+        TranscriptionEngine::Local => println!("Transcribing locally"),
+    }
+    if let Some(parameter) = &config.audio.push_to_talk_parameter {
+        println!("Push-to-talk parameter: {}", parameter);
+    }
+    if let Some(listen) = &config.listen {
+        println!("Translating other players to: {}", listen.target_language);
+    }
 
-    let (tx, mut rx) = mpsc::channel::<AudioEvent>(100);
+    let (tx, mut rx) = new_event_queue(&config);
 
     let typing_indicator = TypingIndicator::new(Arc::clone(&socket), Arc::clone(&config));
+    let mut level_monitor = LevelMonitor::new(Arc::clone(&socket), Arc::clone(&config));
+
+    let controls = Arc::new(RecorderControls::default());
+    let listener = ParameterListener::new(&config, Arc::clone(&controls));
+    tokio::spawn(listener.run(Arc::clone(&socket)));
 
     // Start the audio recording in a separate thread
     let config_clone = Arc::clone(&config);
     std::thread::spawn(move || {
-        if let Err(e) = start_audio_recording(&config_clone, tx) {
+        let replay = args.replay.as_deref();
+        if let Err(e) = record_audio(&config_clone, replay, args.replay_speed, controls, tx) {
             eprintln!("Error starting audio recording: {}", e);
         }
     });
 
-    let mut rate_limiter = RateLimiter::new(config.rate_limit.requests_per_minute);
-    let mut price_estimator = PriceEstimator::new(&config.openai.model);
-    println!("Loaded total cost: ${:.4}", price_estimator.total_cost);
+    let price_estimator = Arc::new(PriceEstimator::new(&config.translation_api().model));
+    println!("Loaded total cost: ${:.4}", price_estimator.total_cost());
+
+    // Optional second pipeline for what other players say, with its own
+    // language hint, prompt history and rate limit, so neither pipeline
+    // waits for the other
+    if let Some(listen_config) = config
+        .listen
+        .as_ref()
+        .map(|listen| listen.pipeline_config(&config))
+    {
+        let (listen_tx, listen_rx) = new_event_queue(&listen_config);
+        let listen_config = Arc::new(listen_config);
+        let recorder_config = Arc::clone(&listen_config);
+        std::thread::spawn(move || {
+            let controls = Arc::new(RecorderControls::default());
+            if let Err(e) = record_microphone(&recorder_config, controls, listen_tx) {
+                eprintln!("Error starting listen audio recording: {}", e);
+            }
+        });
+
+        let translator = Translator::new(transcriber.fork(&listen_config), &listen_config);
+        let output_file = config
+            .listen
+            .as_ref()
+            .and_then(|listen| listen.output_file.clone());
+        tokio::spawn(run_listen_pipeline(
+            listen_rx,
+            listen_config,
+            translator,
+            Arc::clone(&price_estimator),
+            output_file,
+        ));
+    }
+
+    let mut translator = Translator::new(transcriber, &config);
 
     let recording_manager = if config.debug {
         Some(RecordingManager::new(PathBuf::from("recordings"), 10))
@@ -94,14 +209,37 @@ async fn run_main() -> Result<(), Box<dyn Error>> {
             AudioEvent::StopRecording => {
                 typing_indicator.stop_typing().await;
             }
-            AudioEvent::AudioData(audio_data) => {
+            AudioEvent::DeviceConnected(name) => {
+                println!("Using input device: {}", name);
+            }
+            AudioEvent::DeviceLost(reason) => {
+                eprintln!("Lost audio input: {}", reason);
+                typing_indicator.stop_typing().await;
+            }
+            AudioEvent::PartialAudio(audio) => {
+                if let Err(e) = process_partial_audio(
+                    audio,
+                    &config,
+                    &mut translator,
+                    &socket,
+                    &price_estimator,
+                )
+                .await
+                {
+                    eprintln!("Error processing partial audio: {}", e);
+                }
+            }
+            AudioEvent::AudioData(audio) => {
+                if let Some(levels) = &audio.levels {
+                    level_monitor.check(levels).await;
+                }
                 if let Err(e) = process_audio(
-                    audio_data,
+                    audio,
                     &config,
+                    &mut translator,
                     &socket,
-                    &mut rate_limiter,
                     &typing_indicator,
-                    &mut price_estimator,
+                    &price_estimator,
                     recording_manager.as_ref(),
                 )
                 .await
@@ -112,5 +250,73 @@ async fn run_main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    let stats = rx.stats();
+    println!(
+        "Utterances dropped: {}, merged: {}",
+        stats.dropped.load(Ordering::Relaxed),
+        stats.merged.load(Ordering::Relaxed)
+    );
+
     Ok(())
 }
+
+/// Translates what other players say, as it comes in from the listen input.
+async fn run_listen_pipeline(
+    mut rx: EventReceiver,
+    config: Arc<Config>,
+    mut translator: Translator,
+    price_estimator: Arc<PriceEstimator>,
+    output_file: Option<PathBuf>,
+) {
+    while let Some(event) = rx.recv().await {
+        match event {
+            AudioEvent::StartRecording
+            | AudioEvent::StopRecording
+            | AudioEvent::PartialAudio(_) => {}
+            AudioEvent::DeviceConnected(name) => {
+                println!("Listening to other players on: {}", name);
+            }
+            AudioEvent::DeviceLost(reason) => {
+                eprintln!("Lost listening input: {}", reason);
+            }
+            AudioEvent::AudioData(audio) => {
+                if let Err(e) = process_heard_audio(
+                    audio,
+                    &config,
+                    &mut translator,
+                    output_file.as_deref(),
+                    &price_estimator,
+                )
+                .await
+                {
+                    eprintln!("Error processing heard audio: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Records from the microphone, or replays saved WAV files when `--replay` is given.
+fn record_audio(
+    config: &Config,
+    replay: Option<&Path>,
+    replay_speed: f32,
+    controls: Arc<RecorderControls>,
+    tx: EventSender,
+) -> Result<(), Box<dyn Error>> {
+    match replay {
+        Some(path) => {
+            let mut source = WavReplaySource::open(path, replay_speed, &config.audio)?;
+            start_audio_recording(config, &mut source, controls, tx)
+        }
+        None => record_microphone(config, controls, tx),
+    }
+}
+
+fn new_event_queue(config: &Config) -> (EventSender, EventReceiver) {
+    event_queue(
+        config.queue.max_utterances,
+        config.queue.overflow,
+        AudioEncoder::new(&config.audio),
+    )
+}
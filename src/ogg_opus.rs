@@ -0,0 +1,101 @@
+//! Mono Ogg/Opus encoding (RFC 7845) for compact uploads.
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::error::Error;
+
+/// Input rates the Opus encoder accepts.
+pub const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+const FRAME_MS: usize = 20;
+/// Upper bound recommended by libopus for a single packet.
+const MAX_PACKET_SIZE: usize = 4000;
+/// Ogg granule positions always count 48 kHz samples, whatever the input rate.
+const GRANULE_RATE: u64 = 48000;
+const STREAM_SERIAL: u32 = 1;
+
+/// Encodes mono float samples as an Ogg/Opus file. `sample_rate` must be one
+/// of [`OPUS_SAMPLE_RATES`].
+pub fn encode_ogg_opus(
+    samples: &[f32],
+    sample_rate: u32,
+    bitrate: i32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let opus_rate = match sample_rate {
+        8000 => SampleRate::Hz8000,
+        12000 => SampleRate::Hz12000,
+        16000 => SampleRate::Hz16000,
+        24000 => SampleRate::Hz24000,
+        48000 => SampleRate::Hz48000,
+        _ => return Err(format!("Opus does not support {} Hz audio", sample_rate).into()),
+    };
+
+    let mut encoder = Encoder::new(opus_rate, Channels::Mono, Application::Voip)?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate))?;
+
+    let granule_scale = GRANULE_RATE / sample_rate as u64;
+    let lookahead = encoder.lookahead()? as usize;
+    let pre_skip = lookahead as u64 * granule_scale;
+
+    let mut writer = PacketWriter::new(Vec::new());
+    writer.write_packet(
+        opus_head(pre_skip as u16, sample_rate).into_boxed_slice(),
+        STREAM_SERIAL,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+    writer.write_packet(
+        opus_tags().into_boxed_slice(),
+        STREAM_SERIAL,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+
+    // Encode enough padded frames to push the encoder's lookahead back out
+    let frame_len = sample_rate as usize * FRAME_MS / 1000;
+    let frame_count = (samples.len() + lookahead).div_ceil(frame_len);
+    let end_granule = pre_skip + samples.len() as u64 * granule_scale;
+
+    let mut frame = vec![0.0f32; frame_len];
+    let mut packet = vec![0u8; MAX_PACKET_SIZE];
+    for index in 0..frame_count {
+        let start = (index * frame_len).min(samples.len());
+        let end = ((index + 1) * frame_len).min(samples.len());
+        frame.fill(0.0);
+        frame[..end - start].copy_from_slice(&samples[start..end]);
+
+        let len = encoder.encode_float(&frame, &mut packet)?;
+
+        let is_last = index + 1 == frame_count;
+        let (granule, end_info) = if is_last {
+            (end_granule, PacketWriteEndInfo::EndStream)
+        } else {
+            let granule = ((index + 1) * frame_len) as u64 * granule_scale;
+            (granule, PacketWriteEndInfo::NormalPacket)
+        };
+        writer.write_packet(packet[..len].into(), STREAM_SERIAL, end_info, granule)?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn opus_head(pre_skip: u16, input_sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(1); // channel count
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // mono/stereo channel mapping
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    let vendor = env!("CARGO_PKG_NAME").as_bytes();
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    tags
+}
@@ -0,0 +1,96 @@
+use crate::config::Config;
+use crate::types::RecorderControls;
+use rosc::{decoder, OscMessage, OscPacket, OscType};
+use std::io::ErrorKind;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Reads avatar parameters that VRChat sends to `osc.input_port` and
+/// forwards the ones the recorder cares about.
+pub struct ParameterListener {
+    push_to_talk_address: Option<String>,
+    respect_mute: bool,
+    controls: Arc<RecorderControls>,
+}
+
+impl ParameterListener {
+    pub fn new(config: &Config, controls: Arc<RecorderControls>) -> Self {
+        ParameterListener {
+            push_to_talk_address: config
+                .audio
+                .push_to_talk_parameter
+                .as_ref()
+                .map(|name| parameter_address(name)),
+            respect_mute: config.audio.respect_mute,
+            controls,
+        }
+    }
+
+    pub async fn run(self, socket: Arc<UdpSocket>) {
+        let mut buf = [0u8; decoder::MTU];
+
+        loop {
+            let len = match socket.recv(&mut buf).await {
+                Ok(len) => len,
+                // Windows reports ICMP "port unreachable" for our own sends as a receive error
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    eprintln!("Error receiving OSC packet: {}", e);
+                    continue;
+                }
+            };
+
+            if let Ok((_, packet)) = decoder::decode_udp(&buf[..len]) {
+                self.handle_packet(&packet);
+            }
+        }
+    }
+
+    fn handle_packet(&self, packet: &OscPacket) {
+        match packet {
+            OscPacket::Message(message) => self.handle_message(message),
+            OscPacket::Bundle(bundle) => {
+                for packet in &bundle.content {
+                    self.handle_packet(packet);
+                }
+            }
+        }
+    }
+
+    fn handle_message(&self, message: &OscMessage) {
+        let value = match message.args.first().and_then(parameter_to_bool) {
+            Some(value) => value,
+            None => return,
+        };
+
+        if self.push_to_talk_address.as_deref() == Some(message.addr.as_str()) {
+            self.controls.push_to_talk.store(value, Ordering::Relaxed);
+        }
+
+        if self.respect_mute && message.addr == MUTE_SELF_ADDRESS {
+            let was_muted = self.controls.muted.swap(value, Ordering::Relaxed);
+            if value && !was_muted {
+                println!("Muted in VRChat. Pausing recording...");
+            } else if !value && was_muted {
+                println!("Unmuted in VRChat. Resuming recording...");
+            }
+        }
+    }
+}
+
+const MUTE_SELF_ADDRESS: &str = "/avatar/parameters/MuteSelf";
+
+pub(crate) fn parameter_address(name: &str) -> String {
+    format!("/avatar/parameters/{}", name)
+}
+
+/// Interprets bool, int and float avatar parameters as on/off.
+fn parameter_to_bool(arg: &OscType) -> Option<bool> {
+    match arg {
+        OscType::Bool(value) => Some(*value),
+        OscType::Int(value) => Some(*value != 0),
+        OscType::Float(value) => Some(*value >= 0.5),
+        _ => None,
+    }
+}
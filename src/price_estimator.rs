@@ -1,16 +1,26 @@
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// Estimates what requests cost and keeps a running total on disk. Both
+/// pipelines add to the same total, so it can be shared between tasks.
 pub struct PriceEstimator {
     whisper_price_per_minute: f64,
     gpt_input_price_per_million_tokens: f64,
     gpt_output_price_per_million_tokens: f64,
-    pub total_cost: f64,
+    cost_file: PathBuf,
+    total_cost: Mutex<f64>,
 }
 
 impl PriceEstimator {
     pub fn new(model: &str) -> Self {
+        Self::with_cost_file(model, "total_cost.txt")
+    }
+
+    /// Like `new`, but keeps the running total in `cost_file`.
+    pub fn with_cost_file(model: &str, cost_file: impl Into<PathBuf>) -> Self {
         let (input_price, output_price) = match model {
             "gpt-4o" => (5.00, 15.00),
             "gpt-4o-2024-08-06" => (2.50, 10.00),
@@ -19,13 +29,15 @@ impl PriceEstimator {
             _ => (0.0, 0.0),
         };
 
-        let total_cost = Self::load_total_cost().unwrap_or(0.0);
+        let cost_file = cost_file.into();
+        let total_cost = Self::load_total_cost(&cost_file).unwrap_or(0.0);
 
         PriceEstimator {
             whisper_price_per_minute: 0.006,
             gpt_input_price_per_million_tokens: input_price,
             gpt_output_price_per_million_tokens: output_price,
-            total_cost,
+            cost_file,
+            total_cost: Mutex::new(total_cost),
         }
     }
 
@@ -42,18 +54,24 @@ impl PriceEstimator {
         input_cost + output_cost
     }
 
-    pub fn add_cost(&mut self, cost: f64) {
-        self.total_cost += cost;
-        self.save_total_cost();
+    pub fn total_cost(&self) -> f64 {
+        *self.total_cost.lock().unwrap()
+    }
+
+    pub fn add_cost(&self, cost: f64) {
+        let mut total_cost = self.total_cost.lock().unwrap();
+        *total_cost += cost;
+        // Saved while still locked, so an older total never overwrites a newer one
+        self.save_total_cost(*total_cost);
     }
 
-    fn load_total_cost() -> Result<f64, Box<dyn Error>> {
-        let content = fs::read_to_string("total_cost.txt")?;
+    fn load_total_cost(cost_file: &Path) -> Result<f64, Box<dyn Error>> {
+        let content = fs::read_to_string(cost_file)?;
         Ok(content.trim().parse()?)
     }
 
-    fn save_total_cost(&self) {
-        if let Err(e) = fs::write("total_cost.txt", self.total_cost.to_string()) {
+    fn save_total_cost(&self, total_cost: f64) {
+        if let Err(e) = fs::write(&self.cost_file, total_cost.to_string()) {
             eprintln!("Failed to save total cost: {}", e);
         }
     }
@@ -34,4 +34,47 @@ impl RateLimiter {
 
         self.request_count += 1;
     }
+
+    /// Takes a request without waiting, but only while more than `reserve`
+    /// are left this minute. Returns whether it got one.
+    pub fn try_acquire(&mut self, reserve: usize) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_request) >= Duration::from_secs(60) {
+            self.request_count = 0;
+            self.last_request = now;
+        }
+
+        if self.request_count + reserve >= self.max_requests {
+            return false;
+        }
+        self.request_count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_acquire_keeps_the_reserve() {
+        let mut limiter = RateLimiter::new(3);
+        assert!(limiter.try_acquire(1));
+        assert!(limiter.try_acquire(1));
+        assert!(!limiter.try_acquire(1));
+        assert!(limiter.try_acquire(0));
+        assert!(!limiter.try_acquire(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_uses_what_try_acquire_left() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.try_acquire(1));
+
+        let started = tokio::time::Instant::now();
+        limiter.wait().await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        limiter.wait().await;
+        assert!(started.elapsed() >= Duration::from_secs(59));
+    }
 }
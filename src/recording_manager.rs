@@ -1,3 +1,6 @@
+use crate::audio_encoding::{encode_wav_pcm16, EncodedAudio, UploadFormat};
+use crate::input_levels::InputLevels;
+use serde::Serialize;
 use std::error::Error;
 use std::fs;
 use std::path::PathBuf;
@@ -5,6 +8,17 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
+/// Extension of the file with input levels saved next to each recording.
+const STATS_EXTENSION: &str = "json";
+
+#[derive(Serialize)]
+struct RecordingStats<'a> {
+    transcription: &'a str,
+    duration_seconds: f32,
+    #[serde(flatten)]
+    levels: &'a InputLevels,
+}
+
 pub struct RecordingManager {
     recordings_dir: PathBuf,
     max_recordings: usize,
@@ -20,7 +34,7 @@ impl RecordingManager {
 
     pub async fn save_recording(
         &self,
-        audio_data: Vec<u8>,
+        audio: EncodedAudio,
         transcription: &str,
     ) -> Result<(), Box<dyn Error>> {
         fs::create_dir_all(&self.recordings_dir)?;
@@ -28,11 +42,33 @@ impl RecordingManager {
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
         let slugified_transcription =
             self.slugify(&transcription[..std::cmp::min(50, transcription.len())]);
-        let filename = format!("{}_{}.wav", timestamp, slugified_transcription);
+        let filename = format!(
+            "{}_{}.{}",
+            timestamp,
+            slugified_transcription,
+            UploadFormat::Wav.extension()
+        );
         let file_path = self.recordings_dir.join(filename);
 
+        // Always WAV, whatever was uploaded, so `--replay` can play it back
+        let data = match audio.format {
+            UploadFormat::Wav => audio.data,
+            _ => encode_wav_pcm16(&audio.samples, audio.sample_rate)?,
+        };
         let mut file = File::create(&file_path).await?;
-        file.write_all(&audio_data).await?;
+        file.write_all(&data).await?;
+
+        // Input levels go next to the recording, to help tell a bad
+        // transcription from a bad microphone setup
+        if let Some(levels) = &audio.levels {
+            let stats = RecordingStats {
+                transcription,
+                duration_seconds: audio.duration.as_secs_f32(),
+                levels,
+            };
+            let stats_path = file_path.with_extension(STATS_EXTENSION);
+            tokio::fs::write(stats_path, serde_json::to_string_pretty(&stats)?).await?;
+        }
 
         self.cleanup_old_recordings().await?;
 
@@ -42,6 +78,9 @@ impl RecordingManager {
     async fn cleanup_old_recordings(&self) -> Result<(), Box<dyn Error>> {
         let mut entries: Vec<_> = fs::read_dir(&self.recordings_dir)?
             .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry.path().extension().and_then(|ext| ext.to_str()) != Some(STATS_EXTENSION)
+            })
             .collect();
 
         entries.sort_by_key(|entry| entry.metadata().unwrap().modified().unwrap());
@@ -49,6 +88,10 @@ impl RecordingManager {
         if entries.len() > self.max_recordings {
             for entry in entries.iter().take(entries.len() - self.max_recordings) {
                 fs::remove_file(entry.path())?;
+                let stats_path = entry.path().with_extension(STATS_EXTENSION);
+                if stats_path.exists() {
+                    fs::remove_file(stats_path)?;
+                }
             }
         }
 
@@ -0,0 +1,119 @@
+//! Helpers shared by the unit tests.
+
+use crate::config::Config;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A config with every required setting. `extra` goes into the `[audio]`
+/// section, which comes last, so it can also start tables of its own such as
+/// `[transcription]`.
+pub fn test_config(extra: &str) -> Config {
+    toml::from_str(&format!(
+        r#"
+debug = false
+
+[osc]
+address = "127.0.0.1"
+input_port = 9001
+output_port = 9000
+display_time = 3000
+max_message_chunks = 9
+
+[openai]
+api_key = "test"
+model = "gpt-4o-mini"
+
+[translation]
+target_language = "Japanese"
+include_original_message = false
+
+[rate_limit]
+requests_per_minute = 50
+
+[audio]
+noise_gate_threshold = 0.1
+min_transcription_duration = 1.0
+{}
+"#,
+        extra
+    ))
+    .expect("test config should parse")
+}
+
+/// An empty directory under the system temp dir, unique to `name` and this
+/// test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("babble_boop_{}_{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("temp dir should be writable");
+    dir
+}
+
+/// Answers every OpenAI-style chat completion request on a local port with
+/// `reply`, and returns the base URL to use for it.
+pub async fn serve_chat_completions(reply: &str) -> String {
+    let body = serde_json::json!({
+        "choices": [{ "message": { "role": "assistant", "content": reply } }]
+    })
+    .to_string();
+    serve_http(move |_| (200, body.clone())).await
+}
+
+/// Answers every HTTP request on a local port with the status and JSON body
+/// `respond` returns for the request text, and returns the base URL.
+pub async fn serve_http<F>(respond: F) -> String
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let respond = Arc::new(respond);
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let respond = Arc::clone(&respond);
+            tokio::spawn(async move {
+                let request = read_request(&mut stream).await;
+                let (status, body) = respond(&request);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    base_url
+}
+
+/// Reads one HTTP request, headers and body, so the client sees a clean reply.
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        request.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&request);
+        if let Some(header_end) = text.find("\r\n\r\n") {
+            let content_length = text[..header_end]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= header_end + 4 + content_length {
+                break;
+            }
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
@@ -1,57 +1,796 @@
-use crate::config::OpenAiConfig;
-use crate::rate_limiter::RateLimiter;
+use crate::audio_encoding::EncodedAudio;
+use crate::config::{
+    ApiSettings, Config, ResponseFormat, TranscriptionConfig, TranscriptionEngine,
+};
+use async_trait::async_trait;
 use serde::Deserialize;
+use std::collections::VecDeque;
 use std::error::Error;
 
-pub async fn transcribe_audio(
-    audio_data: Vec<u8>,
-    config: &OpenAiConfig,
-    rate_limiter: &mut RateLimiter,
-) -> Result<String, Box<dyn Error>> {
-    println!(
-        "Starting audio transcription. Audio data size: {} bytes",
-        audio_data.len()
-    );
+/// Turns a recorded utterance into text. The text is empty when everything
+/// Whisper heard was dropped as a likely hallucination.
+#[async_trait]
+pub trait Transcriber: Send {
+    async fn transcribe(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>>;
 
-    if audio_data.is_empty() {
-        return Err("Audio data is empty".into());
+    /// Transcribes a snapshot of an utterance that is still going on. The
+    /// result is not added to the prompt context, the final one will be.
+    async fn transcribe_partial(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>>;
+
+    /// Whether the engine costs nothing to use, e.g. a local model or a
+    /// self-hosted server.
+    fn is_free(&self) -> bool {
+        false
+    }
+
+    /// Creates a transcriber for another pipeline that uses the language and
+    /// prompt settings from `config`, but shares this one's model.
+    fn fork(&self, config: &Config) -> Box<dyn Transcriber>;
+}
+
+/// Whisper only looks at roughly the last 224 tokens of the prompt.
+const MAX_PROMPT_CHARS: usize = 800;
+
+/// Hints that steer Whisper towards the right language and spelling: the
+/// configured language and temperature, and a prompt made of the configured
+/// text, the glossary and the most recent transcriptions.
+pub struct TranscriptionHints {
+    pub language: Option<String>,
+    pub temperature: Option<f32>,
+    fixed_prompt: Option<String>,
+    history: VecDeque<String>,
+    max_history: usize,
+}
+
+impl TranscriptionHints {
+    pub fn new(config: &TranscriptionConfig) -> Self {
+        let glossary = (!config.glossary.is_empty()).then(|| config.glossary.join(", "));
+        let fixed_prompt = [config.prompt.clone(), glossary]
+            .into_iter()
+            .flatten()
+            .filter(|part| !part.trim().is_empty())
+            .collect::<Vec<_>>();
+
+        let mut fixed_prompt = fixed_prompt.join("\n");
+        let fixed_len = fixed_prompt.chars().count();
+        if fixed_len > MAX_PROMPT_CHARS {
+            println!(
+                "Warning: `prompt` and `glossary` are {} characters long together, but Whisper only reads about the last {}. \
+                 The start is left out and there is no room for recent transcriptions.",
+                fixed_len, MAX_PROMPT_CHARS
+            );
+            fixed_prompt = fixed_prompt
+                .chars()
+                .skip(fixed_len - MAX_PROMPT_CHARS)
+                .collect();
+        }
+
+        TranscriptionHints {
+            language: config.language.clone(),
+            temperature: config.temperature,
+            fixed_prompt: (!fixed_prompt.is_empty()).then_some(fixed_prompt),
+            history: VecDeque::with_capacity(config.prompt_history),
+            max_history: config.prompt_history,
+        }
+    }
+
+    /// The prompt for the next utterance. Recent transcriptions come last,
+    /// since Whisper pays most attention to the end of the prompt, and the
+    /// oldest ones are left out if it gets too long.
+    pub fn prompt(&self) -> Option<String> {
+        let fixed_len = self
+            .fixed_prompt
+            .as_ref()
+            .map_or(0, |prompt| prompt.chars().count());
+        let mut history_len = 0;
+        let recent = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|text| {
+                history_len += text.chars().count() + 1;
+                fixed_len + history_len <= MAX_PROMPT_CHARS
+            })
+            .count();
+
+        let parts: Vec<&str> = self
+            .fixed_prompt
+            .iter()
+            .chain(self.history.iter().skip(self.history.len() - recent))
+            .map(String::as_str)
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("\n"))
+    }
+
+    /// Adds a transcription to the context for the next utterances.
+    pub fn remember(&mut self, transcription: &str) {
+        if self.max_history == 0 {
+            return;
+        }
+        if self.history.len() == self.max_history {
+            self.history.pop_front();
+        }
+        self.history.push_back(transcription.to_string());
+    }
+}
+
+/// Part of a transcription, with Whisper's confidence in it when the engine
+/// reports it.
+pub struct Segment {
+    pub text: String,
+    pub no_speech_prob: Option<f32>,
+    pub avg_logprob: Option<f32>,
+}
+
+/// Drops segments Whisper most likely made up from breaths and noise: ones it
+/// thinks contain no speech, ones it is unsure of, and known phantom phrases
+/// such as "Thanks for watching!".
+///
+/// Either threshold is enough to drop a segment. Whisper itself only calls a
+/// segment silence when both are crossed, and retries unsure ones at a higher
+/// temperature. The API gives no way to retry a single segment, and the
+/// phantom phrases it invents for silence often come back with a confident
+/// log probability, so the stricter rule lets too many through here.
+pub struct HallucinationFilter {
+    no_speech_threshold: f32,
+    logprob_threshold: f32,
+    blocklist: Vec<String>,
+}
+
+impl HallucinationFilter {
+    pub fn new(config: &TranscriptionConfig) -> Self {
+        HallucinationFilter {
+            no_speech_threshold: config.no_speech_threshold,
+            logprob_threshold: config.logprob_threshold,
+            blocklist: config
+                .hallucination_blocklist
+                .iter()
+                .map(|phrase| normalize_phrase(phrase))
+                .filter(|phrase| !phrase.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Joins the segments that pass into one transcription, logging the rest.
+    pub fn filter(&self, segments: Vec<Segment>) -> String {
+        let mut text = String::new();
+        for segment in segments {
+            match self.drop_reason(&segment) {
+                Some(reason) => println!(
+                    "Dropped likely hallucination ({}): {}",
+                    reason,
+                    segment.text.trim()
+                ),
+                None => text.push_str(&segment.text),
+            }
+        }
+        text.trim().to_string()
+    }
+
+    fn drop_reason(&self, segment: &Segment) -> Option<String> {
+        if let Some(prob) = segment
+            .no_speech_prob
+            .filter(|&prob| prob > self.no_speech_threshold)
+        {
+            return Some(format!("no speech probability {:.2}", prob));
+        }
+        if let Some(logprob) = segment
+            .avg_logprob
+            .filter(|&logprob| logprob < self.logprob_threshold)
+        {
+            return Some(format!("average log probability {:.2}", logprob));
+        }
+
+        let text = normalize_phrase(&segment.text);
+        self.blocklist
+            .iter()
+            .find(|phrase| text.contains(phrase.as_str()))
+            .map(|phrase| format!("blocklisted \"{}\"", phrase))
+    }
+}
+
+/// Lowercases and strips punctuation so "Thanks for watching!" matches
+/// "thanks for watching".
+fn normalize_phrase(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Creates the engine selected in `[transcription]`. Loading a local model
+/// can take a few seconds, so this should happen once at startup.
+pub fn create_transcriber(config: &Config) -> Result<Box<dyn Transcriber>, Box<dyn Error>> {
+    match config.transcription.engine {
+        TranscriptionEngine::OpenAi => Ok(Box::new(OpenAiTranscriber::new(config))),
+        TranscriptionEngine::Local => create_local_transcriber(config),
+    }
+}
+
+/// OpenAI's Whisper API, or any server that offers the same endpoint.
+pub struct OpenAiTranscriber {
+    api: ApiSettings,
+    hints: TranscriptionHints,
+    filter: HallucinationFilter,
+    response_format: ResponseFormat,
+    /// Whether to retry with `json` if the server rejects `verbose_json`,
+    /// which gpt-4o-transcribe and some compatible servers do.
+    json_fallback: bool,
+}
+
+impl OpenAiTranscriber {
+    pub fn new(config: &Config) -> Self {
+        let response_format = config.transcription.response_format;
+        OpenAiTranscriber {
+            api: config.transcription_api(),
+            hints: TranscriptionHints::new(&config.transcription),
+            filter: HallucinationFilter::new(&config.transcription),
+            response_format: response_format.unwrap_or(ResponseFormat::VerboseJson),
+            json_fallback: response_format.is_none(),
+        }
+    }
+
+    async fn recognize(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>> {
+        println!(
+            "Starting audio transcription. Audio data size: {} bytes ({})",
+            audio.data.len(),
+            audio.format.extension()
+        );
+
+        if audio.data.is_empty() {
+            return Err("Audio data is empty".into());
+        }
+
+        let client = reqwest::Client::new();
+        let mut res = self.send(&client, &audio, self.response_format).await?;
+
+        if res.status().is_client_error() && self.json_fallback {
+            let error_text = res.text().await?;
+            let retry = self.send(&client, &audio, ResponseFormat::Json).await?;
+            if !retry.status().is_success() {
+                return Err(format!("API request failed: {}", error_text).into());
+            }
+            println!(
+                "Warning: the transcription server does not accept response_format = \"verbose_json\" ({}). \
+                 Using \"json\" from now on, so only the blocklist filters out hallucinations. \
+                 Set response_format = \"json\" in [transcription] to skip this check.",
+                error_text.trim()
+            );
+            self.response_format = ResponseFormat::Json;
+            self.json_fallback = false;
+            res = retry;
+        }
+
+        if !res.status().is_success() {
+            let error_text = res.text().await?;
+            return Err(format!("API request failed: {}", error_text).into());
+        }
+
+        #[derive(Deserialize)]
+        struct TranscriptionResponse {
+            text: String,
+            /// Not every OpenAI-compatible server returns segments.
+            #[serde(default)]
+            segments: Vec<ResponseSegment>,
+        }
+
+        #[derive(Deserialize)]
+        struct ResponseSegment {
+            text: String,
+            no_speech_prob: Option<f32>,
+            avg_logprob: Option<f32>,
+        }
+
+        let transcription: TranscriptionResponse = res.json().await?;
+        println!("Transcription received: {}", transcription.text);
+
+        if transcription.text.is_empty() {
+            return Err("Received empty transcription from API".into());
+        }
+
+        let segments = if transcription.segments.is_empty() {
+            vec![Segment {
+                text: transcription.text,
+                no_speech_prob: None,
+                avg_logprob: None,
+            }]
+        } else {
+            transcription
+                .segments
+                .into_iter()
+                .map(|segment| Segment {
+                    text: segment.text,
+                    no_speech_prob: segment.no_speech_prob,
+                    avg_logprob: segment.avg_logprob,
+                })
+                .collect()
+        };
+
+        Ok(self.filter.filter(segments))
+    }
+
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        audio: &EncodedAudio,
+        response_format: ResponseFormat,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
+        let part = reqwest::multipart::Part::bytes(audio.data.clone())
+            .file_name(audio.file_name())
+            .mime_str(audio.format.mime_type())?;
+
+        let mut form = reqwest::multipart::Form::new()
+            .part("file", part)
+            .text("model", self.api.model.clone())
+            .text("response_format", response_format.as_str());
+        if let Some(language) = &self.hints.language {
+            form = form.text("language", language.clone());
+        }
+        if let Some(temperature) = self.hints.temperature {
+            form = form.text("temperature", temperature.to_string());
+        }
+        if let Some(prompt) = self.hints.prompt() {
+            form = form.text("prompt", prompt);
+        }
+
+        println!("Sending request to {}", self.api.base_url);
+        Ok(self
+            .api
+            .apply_to(client.post(self.api.endpoint("audio/transcriptions")))
+            .multipart(form)
+            .send()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Transcriber for OpenAiTranscriber {
+    async fn transcribe(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>> {
+        let text = self.recognize(audio).await?;
+        if !text.is_empty() {
+            self.hints.remember(&text);
+        }
+        Ok(text)
+    }
+
+    async fn transcribe_partial(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>> {
+        self.recognize(audio).await
+    }
+
+    fn is_free(&self) -> bool {
+        !self.api.is_openai()
+    }
+
+    fn fork(&self, config: &Config) -> Box<dyn Transcriber> {
+        Box::new(OpenAiTranscriber::new(config))
+    }
+}
+
+#[cfg(feature = "whisper")]
+fn create_local_transcriber(config: &Config) -> Result<Box<dyn Transcriber>, Box<dyn Error>> {
+    Ok(Box::new(local::LocalTranscriber::new(config)?))
+}
+
+#[cfg(not(feature = "whisper"))]
+fn create_local_transcriber(_: &Config) -> Result<Box<dyn Transcriber>, Box<dyn Error>> {
+    Err(
+        "engine = \"local\" needs the `whisper` feature, rebuild with `cargo build --features whisper`"
+            .into(),
+    )
+}
+
+#[cfg(feature = "whisper")]
+mod local {
+    use super::{HallucinationFilter, Segment, Transcriber, TranscriptionHints};
+    use crate::audio_conditioning::resample;
+    use crate::audio_encoding::EncodedAudio;
+    use crate::config::Config;
+    use async_trait::async_trait;
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+    /// Whisper models only take 16 kHz mono input.
+    const WHISPER_SAMPLE_RATE: u32 = 16000;
+
+    /// Runs a Whisper GGML/GGUF model in-process on the CPU.
+    pub struct LocalTranscriber {
+        context: Arc<WhisperContext>,
+        threads: usize,
+        hints: TranscriptionHints,
+        filter: HallucinationFilter,
+        no_speech_threshold: f32,
+    }
+
+    impl LocalTranscriber {
+        pub fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+            let model_path = config
+                .transcription
+                .model_path
+                .as_ref()
+                .ok_or("engine = \"local\" needs `model_path` set to a Whisper model file")?;
+            let model_path = model_path
+                .to_str()
+                .ok_or("`model_path` is not valid UTF-8")?;
+
+            println!("Loading Whisper model from {}...", model_path);
+            let context =
+                WhisperContext::new_with_params(model_path, WhisperContextParameters::default())
+                    .map_err(|e| format!("Could not load Whisper model {}: {}", model_path, e))?;
+
+            let threads = config.transcription.threads.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(4, |threads| threads.get())
+            });
+
+            Ok(LocalTranscriber {
+                context: Arc::new(context),
+                threads,
+                hints: TranscriptionHints::new(&config.transcription),
+                filter: HallucinationFilter::new(&config.transcription),
+                no_speech_threshold: config.transcription.no_speech_threshold,
+            })
+        }
+    }
+
+    impl LocalTranscriber {
+        async fn recognize(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>> {
+            let samples = if audio.sample_rate == WHISPER_SAMPLE_RATE {
+                audio.samples
+            } else {
+                resample(&audio.samples, audio.sample_rate, WHISPER_SAMPLE_RATE)?
+            };
+            if samples.is_empty() {
+                return Err("Audio data is empty".into());
+            }
+
+            println!(
+                "Starting local transcription of {:.1}s of audio",
+                audio.duration.as_secs_f32()
+            );
+            let started = Instant::now();
+
+            // Inference takes hundreds of milliseconds, keep it off the async runtime
+            let context = Arc::clone(&self.context);
+            let threads = self.threads;
+            let language = self.hints.language.clone();
+            let temperature = self.hints.temperature;
+            let prompt = self.hints.prompt();
+            let no_speech_threshold = self.no_speech_threshold;
+            let segments = tokio::task::spawn_blocking(move || {
+                let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+                params.set_n_threads(threads as i32);
+                // The default is English only, but players may speak anything
+                params.set_language(Some(language.as_deref().unwrap_or("auto")));
+                if let Some(temperature) = temperature {
+                    params.set_temperature(temperature);
+                }
+                if let Some(prompt) = &prompt {
+                    params.set_initial_prompt(prompt);
+                }
+                // whisper.cpp skips segments it thinks are silence on its own
+                params.set_no_speech_thold(no_speech_threshold);
+                run_whisper(&context, params, &samples)
+            })
+            .await?
+            .map_err(|e| format!("Local transcription failed: {}", e))?;
+            let text = self.filter.filter(segments);
+
+            println!(
+                "Transcription finished in {:.2}s: {}",
+                started.elapsed().as_secs_f32(),
+                text
+            );
+
+            Ok(text)
+        }
+    }
+
+    #[async_trait]
+    impl Transcriber for LocalTranscriber {
+        async fn transcribe(&mut self, audio: EncodedAudio) -> Result<String, Box<dyn Error>> {
+            let text = self.recognize(audio).await?;
+            if !text.is_empty() {
+                self.hints.remember(&text);
+            }
+            Ok(text)
+        }
+
+        async fn transcribe_partial(
+            &mut self,
+            audio: EncodedAudio,
+        ) -> Result<String, Box<dyn Error>> {
+            self.recognize(audio).await
+        }
+
+        fn is_free(&self) -> bool {
+            true
+        }
+
+        fn fork(&self, config: &Config) -> Box<dyn Transcriber> {
+            Box::new(LocalTranscriber {
+                context: Arc::clone(&self.context),
+                threads: self.threads,
+                hints: TranscriptionHints::new(&config.transcription),
+                filter: HallucinationFilter::new(&config.transcription),
+                no_speech_threshold: config.transcription.no_speech_threshold,
+            })
+        }
     }
 
-    rate_limiter.wait().await;
+    fn run_whisper(
+        context: &WhisperContext,
+        mut params: FullParams,
+        samples: &[f32],
+    ) -> Result<Vec<Segment>, whisper_rs::WhisperError> {
+        let mut state = context.create_state()?;
 
-    let client = reqwest::Client::new();
-    let part = reqwest::multipart::Part::bytes(audio_data)
-        .file_name("audio.wav")
-        .mime_str("audio/wav")?;
+        params.set_print_special(false);
+        params.set_print_progress(false);
+        params.set_print_realtime(false);
+        params.set_print_timestamps(false);
 
-    let form = reqwest::multipart::Form::new()
-        .part("file", part)
-        .text("model", "whisper-1");
+        state.full(params, samples)?;
 
-    println!("Sending request to OpenAI Whisper API");
-    let res = client
-        .post("https://api.openai.com/v1/audio/transcriptions")
-        .header("Authorization", format!("Bearer {}", &config.api_key))
-        .multipart(form)
-        .send()
-        .await?;
+        // Special and timestamp tokens have ids from end-of-text up. They are
+        // nearly always certain and would hide how unsure the words are
+        let token_eot = context.token_eot();
 
-    if !res.status().is_success() {
-        let error_text = res.text().await?;
-        return Err(format!("API request failed: {}", error_text).into());
+        let mut segments = Vec::new();
+        for segment in 0..state.full_n_segments()? {
+            let mut logprob_sum = 0.0;
+            let mut text_tokens = 0;
+            for token in 0..state.full_n_tokens(segment)? {
+                if state.full_get_token_id(segment, token)? >= token_eot {
+                    continue;
+                }
+                logprob_sum += state.full_get_token_prob(segment, token)?.max(1e-10).ln();
+                text_tokens += 1;
+            }
+
+            segments.push(Segment {
+                text: state.full_get_segment_text(segment)?,
+                // Not exposed by whisper.cpp, which applies the threshold itself
+                no_speech_prob: None,
+                avg_logprob: (text_tokens > 0).then(|| logprob_sum / text_tokens as f32),
+            });
+        }
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_encoding::AudioEncoder;
+    use crate::test_support::{serve_http, test_config};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn hints(prompt: Option<&str>, glossary: &[&str], prompt_history: usize) -> TranscriptionHints {
+        TranscriptionHints::new(&TranscriptionConfig {
+            prompt: prompt.map(str::to_string),
+            glossary: glossary.iter().map(|term| term.to_string()).collect(),
+            prompt_history,
+            ..TranscriptionConfig::default()
+        })
     }
 
-    #[derive(Deserialize)]
-    struct TranscriptionResponse {
-        text: String,
+    #[test]
+    fn puts_the_fixed_prompt_and_glossary_before_recent_transcriptions() {
+        let mut hints = hints(Some("Welcome to the Black Cat!"), &["Kyuubi", "avali"], 2);
+        assert_eq!(
+            hints.prompt().unwrap(),
+            "Welcome to the Black Cat!\nKyuubi, avali"
+        );
+
+        for text in ["one", "two", "three"] {
+            hints.remember(text);
+        }
+        assert_eq!(
+            hints.prompt().unwrap(),
+            "Welcome to the Black Cat!\nKyuubi, avali\ntwo\nthree"
+        );
     }
 
-    let transcription: TranscriptionResponse = res.json().await?;
-    println!("Transcription received: {}", transcription.text);
+    #[test]
+    fn leaves_out_the_oldest_transcriptions_past_the_limit() {
+        let mut hints = hints(None, &[], 5);
+        for text in ["a", "b", "c", "d"] {
+            hints.remember(&text.repeat(300));
+        }
+
+        // Two transcriptions and their separators fit in 800 characters, three do not
+        let prompt = hints.prompt().unwrap();
+        assert_eq!(prompt, format!("{}\n{}", "c".repeat(300), "d".repeat(300)));
+        assert!(prompt.chars().count() <= MAX_PROMPT_CHARS);
+    }
 
-    if transcription.text.is_empty() {
-        return Err("Received empty transcription from API".into());
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        let mut hints = hints(None, &[], 5);
+        for _ in 0..3 {
+            // 200 characters, 600 bytes
+            hints.remember(&"あ".repeat(200));
+        }
+        assert_eq!(hints.prompt().unwrap().lines().count(), 3);
     }
 
-    Ok(transcription.text)
+    #[test]
+    fn keeps_the_end_of_a_fixed_prompt_over_the_limit() {
+        let prompt = format!("{}{}", "x".repeat(500), "y".repeat(MAX_PROMPT_CHARS));
+        let mut hints = hints(Some(&prompt), &[], 2);
+        hints.remember("recent");
+
+        assert_eq!(hints.prompt().unwrap(), "y".repeat(MAX_PROMPT_CHARS));
+    }
+
+    #[test]
+    fn has_no_prompt_without_history_or_fixed_text() {
+        let mut empty = hints(None, &[], 0);
+        empty.remember("ignored");
+        assert_eq!(empty.prompt(), None);
+
+        let mut blank = hints(Some("  "), &[], 0);
+        blank.remember("ignored");
+        assert_eq!(blank.prompt(), None);
+    }
+
+    fn segment(text: &str, no_speech_prob: Option<f32>, avg_logprob: Option<f32>) -> Segment {
+        Segment {
+            text: text.to_string(),
+            no_speech_prob,
+            avg_logprob,
+        }
+    }
+
+    /// The default filter: no-speech above 0.6 or log probability below -1.0.
+    fn filter(segments: Vec<Segment>) -> String {
+        HallucinationFilter::new(&TranscriptionConfig::default()).filter(segments)
+    }
+
+    #[test]
+    fn normalizes_case_punctuation_and_spacing() {
+        assert_eq!(
+            normalize_phrase("  Thanks   for WATCHING!!"),
+            "thanks for watching"
+        );
+        assert_eq!(
+            normalize_phrase("Subtitles by: Amara.org"),
+            "subtitles by amara org"
+        );
+        assert_eq!(
+            normalize_phrase("ご視聴、ありがとうございました。"),
+            "ご視聴 ありがとうございました"
+        );
+        assert_eq!(normalize_phrase("...!?"), "");
+    }
+
+    #[test]
+    fn drops_segments_past_either_threshold() {
+        // Exactly on a threshold still passes
+        assert_eq!(
+            filter(vec![segment(" kept", Some(0.6), Some(-1.0))]),
+            "kept"
+        );
+        assert_eq!(
+            filter(vec![segment(" silence", Some(0.61), Some(-0.2))]),
+            ""
+        );
+        assert_eq!(filter(vec![segment(" unsure", Some(0.1), Some(-1.01))]), "");
+        assert_eq!(
+            filter(vec![
+                segment(" Hello", Some(0.1), Some(-0.3)),
+                segment(" mumble", Some(0.9), Some(-1.5)),
+                segment(" there.", None, None),
+            ]),
+            "Hello there."
+        );
+    }
+
+    #[test]
+    fn matches_the_blocklist_without_case_or_punctuation() {
+        let config = TranscriptionConfig {
+            hallucination_blocklist: vec!["Thanks for watching!".to_string(), "?!".to_string()],
+            ..TranscriptionConfig::default()
+        };
+        let filter = HallucinationFilter::new(&config);
+
+        assert_eq!(
+            filter.filter(vec![segment(" THANKS, for watching...", None, None)]),
+            ""
+        );
+        assert_eq!(
+            filter.filter(vec![segment(" Well thanks for  watching", None, None)]),
+            ""
+        );
+        // A phrase that is only punctuation would match everything, so it is ignored
+        assert_eq!(
+            filter.filter(vec![segment(" Thanks for coming?!", None, None)]),
+            "Thanks for coming?!"
+        );
+    }
+
+    #[tokio::test]
+    async fn filters_the_whole_text_when_the_server_returns_no_segments() {
+        let base_url = serve_http(|request| {
+            let text = if request.contains("first") {
+                "Thank you for watching!"
+            } else {
+                "hello"
+            };
+            (200, serde_json::json!({ "text": text }).to_string())
+        })
+        .await;
+        let mut first = transcriber("[transcription]\nprompt = \"first\"", base_url.clone());
+        let mut second = transcriber("", base_url);
+
+        // Without confidence only the blocklist applies
+        assert_eq!(first.transcribe(utterance()).await.unwrap(), "");
+        assert_eq!(second.transcribe(utterance()).await.unwrap(), "hello");
+    }
+
+    /// A transcription server that, like gpt-4o-transcribe, rejects
+    /// `verbose_json`. Returns its base URL and a count of requests.
+    async fn json_only_server() -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let base_url = serve_http(move |request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if request.contains("verbose_json") {
+                let error =
+                    r#"{"error":{"message":"response_format 'verbose_json' is not compatible"}}"#;
+                (400, error.to_string())
+            } else {
+                (200, r#"{"text":"hello"}"#.to_string())
+            }
+        })
+        .await;
+        (base_url, requests)
+    }
+
+    fn transcriber(extra: &str, base_url: String) -> OpenAiTranscriber {
+        let mut config = test_config(extra);
+        config.transcription.provider.base_url = Some(base_url);
+        OpenAiTranscriber::new(&config)
+    }
+
+    fn utterance() -> EncodedAudio {
+        AudioEncoder::new(&test_config("").audio)
+            .encode(&vec![0.1; 16000], 16000)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_json_once_when_verbose_json_is_rejected() {
+        let (base_url, requests) = json_only_server().await;
+        let mut transcriber = transcriber("", base_url);
+
+        assert_eq!(transcriber.transcribe(utterance()).await.unwrap(), "hello");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // Later utterances go straight to `json`
+        assert_eq!(transcriber.transcribe(utterance()).await.unwrap(), "hello");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn uses_the_configured_response_format_without_fallback() {
+        let (base_url, requests) = json_only_server().await;
+        let mut json = transcriber(
+            "[transcription]\nresponse_format = \"json\"",
+            base_url.clone(),
+        );
+        assert_eq!(json.transcribe(utterance()).await.unwrap(), "hello");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let mut verbose_json = transcriber(
+            "[transcription]\nresponse_format = \"verbose_json\"",
+            base_url,
+        );
+        assert!(verbose_json.transcribe(utterance()).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
 }
@@ -1,4 +1,4 @@
-use crate::config::OpenAiConfig;
+use crate::config::ApiSettings;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 
@@ -24,20 +24,19 @@ struct ChatGptChoice {
     message: ChatGptMessage,
 }
 
-pub async fn ask_chatgpt(prompt: &str, config: &OpenAiConfig) -> Result<String, Box<dyn Error>> {
+pub async fn ask_chatgpt(prompt: &str, api: &ApiSettings) -> Result<String, Box<dyn Error>> {
     let client = reqwest::Client::new();
 
     let request_body = ChatGptRequest {
-        model: config.model.clone(),
+        model: api.model.clone(),
         messages: vec![ChatGptMessage {
             role: "user".to_string(),
             content: prompt.to_string(),
         }],
     };
 
-    let res = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .bearer_auth(&config.api_key)
+    let res = api
+        .apply_to(client.post(api.endpoint("chat/completions")))
         .json(&request_body)
         .send()
         .await?;
@@ -1,5 +1,22 @@
+use crate::audio_encoding::EncodedAudio;
+use std::sync::atomic::AtomicBool;
+
 pub enum AudioEvent {
     StartRecording,
     StopRecording,
-    AudioData(Vec<u8>),
+    AudioData(EncodedAudio),
+    /// Snapshot of an utterance that is still being recorded, for live
+    /// partial transcription. The final `AudioData` follows.
+    PartialAudio(EncodedAudio),
+    /// The recorder opened an input device, identified by name.
+    DeviceConnected(String),
+    /// The input stream failed; the recorder keeps trying to reopen it.
+    DeviceLost(String),
+}
+
+/// Recorder state driven by avatar parameters received over OSC.
+#[derive(Default)]
+pub struct RecorderControls {
+    pub push_to_talk: AtomicBool,
+    pub muted: AtomicBool,
 }
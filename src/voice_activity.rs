@@ -0,0 +1,282 @@
+use crate::config::{AudioConfig, VadKind};
+use std::collections::VecDeque;
+
+/// Decides whether a stream of interleaved samples currently contains speech.
+pub trait VoiceActivityDetector: Send {
+    /// Feeds the next block of samples and returns whether voice is active.
+    fn process(&mut self, samples: &[f32]) -> bool;
+
+    /// Samples since voice was last heard. Voice stays active for the hold
+    /// time after that, so this is how much of it was only held open.
+    fn samples_since_voice(&self) -> usize;
+}
+
+/// Creates the detector selected by `[audio] vad`.
+pub fn create_detector(
+    config: &AudioConfig,
+    sample_rate: u32,
+    channels: u16,
+) -> Box<dyn VoiceActivityDetector> {
+    let hold_frames = config.noise_gate_hold_duration_ms() * sample_rate as u64 / 1000;
+    let hold_samples = hold_frames as usize * channels as usize;
+
+    match config.vad {
+        VadKind::Peak => Box::new(NoiseGate::new(config.noise_gate_threshold, hold_samples)),
+        VadKind::Energy => Box::new(EnergyDetector::new(
+            config.energy_open_db,
+            config.energy_close_db,
+            hold_samples,
+            // 10 ms analysis frames
+            (sample_rate as usize / 100).max(1) * channels as usize,
+        )),
+    }
+}
+
+/// Opens when any sample's amplitude crosses a fixed threshold.
+pub struct NoiseGate {
+    threshold: f32,
+    hold_samples: usize,
+    samples_since_active: usize,
+    is_active: bool,
+}
+
+impl NoiseGate {
+    pub fn new(threshold: f32, hold_samples: usize) -> Self {
+        NoiseGate {
+            threshold,
+            hold_samples,
+            samples_since_active: 0,
+            is_active: false,
+        }
+    }
+}
+
+impl VoiceActivityDetector for NoiseGate {
+    fn process(&mut self, samples: &[f32]) -> bool {
+        let max_amplitude = samples.iter().map(|&s| s.abs()).fold(0.0f32, f32::max);
+
+        if max_amplitude > self.threshold {
+            self.samples_since_active = 0;
+            self.is_active = true;
+        } else if self.is_active {
+            self.samples_since_active += samples.len();
+            if self.samples_since_active > self.hold_samples {
+                self.is_active = false;
+            }
+        }
+
+        self.is_active
+    }
+
+    fn samples_since_voice(&self) -> usize {
+        self.samples_since_active
+    }
+}
+
+/// Compares the RMS energy of short frames against an adaptive noise floor.
+///
+/// The detector opens when a frame is `open_db` above the floor and closes
+/// once frames have stayed below `close_db` above the floor for the hold
+/// time. The floor follows the quietest recent frames while the detector is
+/// closed, so it adapts to fans and room tone without chasing the speaker.
+///
+/// While open, only the quietest frame of the last second can raise the
+/// floor, and by no more than 10 dB. Speech dips between syllables, so it
+/// does not become the new floor, but background noise that gets louder
+/// mid-utterance is steady and lets the detector close once the floor has
+/// caught up with it.
+pub struct EnergyDetector {
+    open_ratio: f32,
+    close_ratio: f32,
+    hold_samples: usize,
+    frame_len: usize,
+    frame_energy: f32,
+    frame_fill: usize,
+    noise_floor: Option<f32>,
+    /// RMS of the most recent frames, for the quietest one while open.
+    recent_frames: VecDeque<f32>,
+    /// Highest the floor may rise to during the current utterance.
+    active_floor_limit: f32,
+    samples_since_active: usize,
+    is_active: bool,
+}
+
+/// Lowest noise floor, so digital silence does not make every click speech.
+const MIN_NOISE_FLOOR: f32 = 1e-4;
+/// Smoothing when the floor falls to a quieter frame (fast) or rises (slow).
+const FLOOR_FALL_RATE: f32 = 0.5;
+const FLOOR_RISE_RATE: f32 = 0.01;
+/// Frames the quietest one is taken from while open, one second of 10 ms frames.
+const RECENT_FRAMES: usize = 100;
+/// How far the floor may rise during one utterance.
+const MAX_ACTIVE_FLOOR_RISE_DB: f32 = 10.0;
+
+impl EnergyDetector {
+    pub fn new(open_db: f32, close_db: f32, hold_samples: usize, frame_len: usize) -> Self {
+        EnergyDetector {
+            open_ratio: db_to_ratio(open_db),
+            close_ratio: db_to_ratio(close_db),
+            hold_samples,
+            frame_len,
+            frame_energy: 0.0,
+            frame_fill: 0,
+            noise_floor: None,
+            recent_frames: VecDeque::with_capacity(RECENT_FRAMES),
+            active_floor_limit: 0.0,
+            samples_since_active: 0,
+            is_active: false,
+        }
+    }
+
+    fn process_frame(&mut self, rms: f32) {
+        let floor = *self.noise_floor.get_or_insert(rms.max(MIN_NOISE_FLOOR));
+        if self.recent_frames.len() == RECENT_FRAMES {
+            self.recent_frames.pop_front();
+        }
+        self.recent_frames.push_back(rms);
+
+        if rms > floor * self.open_ratio {
+            if !self.is_active {
+                self.active_floor_limit = floor * db_to_ratio(MAX_ACTIVE_FLOOR_RISE_DB);
+            }
+            self.samples_since_active = 0;
+            self.is_active = true;
+        } else if self.is_active {
+            if rms > floor * self.close_ratio {
+                self.samples_since_active = 0;
+            } else {
+                self.samples_since_active += self.frame_len;
+                if self.samples_since_active > self.hold_samples {
+                    self.is_active = false;
+                }
+            }
+        }
+
+        let target = if self.is_active {
+            let quietest = self.recent_frames.iter().copied().fold(f32::MAX, f32::min);
+            quietest.min(self.active_floor_limit)
+        } else {
+            rms
+        };
+        let rate = if target < floor {
+            FLOOR_FALL_RATE
+        } else {
+            FLOOR_RISE_RATE
+        };
+        let floor = floor + (target - floor) * rate;
+        self.noise_floor = Some(floor.max(MIN_NOISE_FLOOR));
+    }
+}
+
+impl VoiceActivityDetector for EnergyDetector {
+    fn process(&mut self, samples: &[f32]) -> bool {
+        for &sample in samples {
+            self.frame_energy += sample * sample;
+            self.frame_fill += 1;
+
+            if self.frame_fill == self.frame_len {
+                let rms = (self.frame_energy / self.frame_len as f32).sqrt();
+                self.frame_energy = 0.0;
+                self.frame_fill = 0;
+                self.process_frame(rms);
+            }
+        }
+
+        self.is_active
+    }
+
+    fn samples_since_voice(&self) -> usize {
+        self.samples_since_active
+    }
+}
+
+pub(crate) fn db_to_ratio(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 16000;
+
+    /// Deterministic white noise at the given RMS level.
+    fn noise(seconds: f32, rms: f32, seed: &mut u32) -> Vec<f32> {
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|_| {
+                *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                // Uniform in [-1, 1) has an RMS of 1/sqrt(3)
+                (*seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * rms * 3f32.sqrt()
+            })
+            .collect()
+    }
+
+    fn detector() -> EnergyDetector {
+        // Defaults: open at 9 dB, close at 4 dB, 500 ms hold, 10 ms frames
+        EnergyDetector::new(9.0, 4.0, SAMPLE_RATE / 2, SAMPLE_RATE / 100)
+    }
+
+    /// Feeds the signal in 10 ms blocks and returns when the detector
+    /// opened and closed, in seconds.
+    fn transitions(detector: &mut EnergyDetector, signal: &[f32]) -> Vec<(f32, bool)> {
+        let mut transitions = Vec::new();
+        let mut was_active = false;
+        for (i, block) in signal.chunks(SAMPLE_RATE / 100).enumerate() {
+            let active = detector.process(block);
+            if active != was_active {
+                transitions.push((i as f32 / 100.0, active));
+                was_active = active;
+            }
+        }
+        transitions
+    }
+
+    #[test]
+    fn opens_on_speech_and_closes_after_hold() {
+        let mut seed = 1;
+        let mut signal = noise(2.0, 0.001, &mut seed);
+        signal.extend(noise(1.0, 0.05, &mut seed));
+        signal.extend(noise(2.0, 0.001, &mut seed));
+
+        let transitions = transitions(&mut detector(), &signal);
+        assert_eq!(transitions.len(), 2, "{:?}", transitions);
+        assert!((transitions[0].0 - 2.0).abs() < 0.05);
+        assert!(transitions[1].0 > 3.4 && transitions[1].0 < 3.7);
+    }
+
+    #[test]
+    fn closes_when_background_noise_rises_during_speech() {
+        // A fan louder than the room starts during the utterance
+        for fan_db in [8.0, 12.0] {
+            let mut seed = 1;
+            let mut signal = noise(2.0, 0.001, &mut seed);
+            signal.extend(noise(1.0, 0.05, &mut seed));
+            signal.extend(noise(60.0, 0.001 * db_to_ratio(fan_db), &mut seed));
+
+            let transitions = transitions(&mut detector(), &signal);
+            assert_eq!(transitions.len(), 2, "{} dB: {:?}", fan_db, transitions);
+            assert!(
+                transitions[1].0 < 10.0,
+                "{} dB: took until {}s to close",
+                fan_db,
+                transitions[1].0
+            );
+        }
+    }
+
+    #[test]
+    fn stays_open_during_long_continuous_speech() {
+        let mut seed = 1;
+        let mut signal = noise(2.0, 0.001, &mut seed);
+        // Steady speech without a single pause, the hardest case for a floor
+        // that adapts
+        signal.extend(noise(15.0, 0.05, &mut seed));
+        signal.extend(noise(2.0, 0.001, &mut seed));
+
+        let transitions = transitions(&mut detector(), &signal);
+        assert_eq!(transitions.len(), 2, "{:?}", transitions);
+        assert!((transitions[0].0 - 2.0).abs() < 0.05);
+        assert!(transitions[1].0 > 17.4 && transitions[1].0 < 17.7);
+    }
+}